@@ -10,13 +10,18 @@ use std::process::exit;
 
 pub(crate) const CONFIG_LOCK_PATH: &str = "AVATAR_CLI_CONFIG_LOCK_PATH";
 pub(crate) const CONFIG_PATH: &str = "AVATAR_CLI_CONFIG_PATH";
+pub(crate) const INTERACTIVE: &str = "AVATAR_CLI_INTERACTIVE";
 pub(crate) const PROCESS_ID: &str = "AVATAR_CLI_PROCESS_ID";
+pub(crate) const PROFILE: &str = "AVATAR_CLI_PROFILE";
 pub(crate) const PROJECT_PATH: &str = "AVATAR_CLI_PROJECT_PATH";
 pub(crate) const PROJECT_INTERNAL_ID: &str = "AVATAR_CLI_PROJECT_INTERNAL_ID";
+pub(crate) const SESSION_PID: &str = "AVATAR_CLI_SESSION_PID";
 pub(crate) const SESSION_TOKEN: &str = "AVATAR_CLI_SESSION_TOKEN";
 pub(crate) const STATE_PATH: &str = "AVATAR_CLI_STATE_PATH";
+pub(crate) const TTY: &str = "AVATAR_CLI_TTY";
 
 pub(crate) struct AvatarEnv {
+    profile: Option<String>,
     project_path: PathBuf,
     session_token: String,
 }
@@ -24,11 +29,16 @@ pub(crate) struct AvatarEnv {
 impl AvatarEnv {
     pub fn read() -> Self {
         Self {
+            profile: get_selected_profile(None),
             project_path: PathBuf::from(Self::get_var(PROJECT_PATH)),
             session_token: Self::get_var(SESSION_TOKEN),
         }
     }
 
+    pub fn get_profile(&self) -> &Option<String> {
+        &self.profile
+    }
+
     pub fn get_project_path(&self) -> &PathBuf {
         &self.project_path
     }
@@ -48,6 +58,26 @@ impl AvatarEnv {
     }
 }
 
+/// The profile passed through the command line takes precedence over the one
+/// set in the environment.
+pub(crate) fn get_selected_profile(cli_profile: Option<&str>) -> Option<String> {
+    match cli_profile {
+        Some(p) => Some(p.to_string()),
+        None => match env::var(PROFILE) {
+            Ok(p) if !p.is_empty() => Some(p),
+            _ => None,
+        },
+    }
+    .map(|p| {
+        // Profile names are part of the state file names
+        if p.contains(MAIN_SEPARATOR) || p.starts_with('.') {
+            eprintln!("Invalid profile name '{}'", p);
+            exit(exitcode::USAGE)
+        }
+        p
+    })
+}
+
 pub(crate) fn get_used_program_name() -> String {
     let first_arg = match env::args().next() {
         Some(a) => a,
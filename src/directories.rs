@@ -9,12 +9,36 @@ use std::path::PathBuf;
 use std::process::exit;
 
 pub(crate) const AVATARFILE_NAME: &str = "Avatarfile";
+pub(crate) const AVATARFILE_LOCAL_NAME: &str = "Avatarfile.local";
 pub(crate) const AVATARFILE_LOCK_NAME: &str = "Avatarfile.lock";
 pub(crate) const CONFIG_DIR_NAME: &str = ".avatar-cli";
 pub(crate) const CONTAINER_HOME_PATH: &str = "/home/avatar-cli";
+pub(crate) const CONTAINER_PROJECT_PATH: &str = "/playground";
+pub(crate) const RUN_INDEX_NAME: &str = "run_index.yml";
 pub(crate) const STATEFILE_NAME: &str = "state.yml";
+pub(crate) const USER_CONFIG_DIR_NAME: &str = "avatar-cli";
 pub(crate) const VOLATILE_DIR_NAME: &str = "volatile";
 
+/// The project state depends on the selected profile, so each profile has its
+/// own state file (e.g. `state.ci.yml`), and sessions using different profiles
+/// don't overwrite each other's files.
+pub(crate) fn get_state_file_name(profile: Option<&str>) -> String {
+    get_profile_file_name(STATEFILE_NAME, profile)
+}
+
+pub(crate) fn get_run_index_file_name(profile: Option<&str>) -> String {
+    get_profile_file_name(RUN_INDEX_NAME, profile)
+}
+
+fn get_profile_file_name(file_name: &str, profile: Option<&str>) -> String {
+    match (profile, file_name.rsplit_once('.')) {
+        (Some(profile_name), Some((stem, extension))) => {
+            format!("{}.{}.{}", stem, profile_name, extension)
+        }
+        _ => file_name.to_string(),
+    }
+}
+
 pub(crate) fn get_project_path() -> Option<PathBuf> {
     let current_dir = match env::current_dir() {
         Ok(p) => p,
@@ -34,6 +58,12 @@ pub(crate) fn get_project_path() -> Option<PathBuf> {
     None
 }
 
+/// Directory where users can keep config files shared across projects, usually
+/// `~/.config/avatar-cli`.
+pub(crate) fn get_user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|p| p.join(USER_CONFIG_DIR_NAME))
+}
+
 pub(crate) fn check_if_inside_project_dir(project_path: &PathBuf, current_dir: &PathBuf) {
     let mut in_project_dir = false;
     for ancestor in current_dir.ancestors() {
@@ -0,0 +1,181 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::process::exit;
+
+/// Reads a dotenv file. It supports comments, empty lines, the `export` prefix,
+/// and single-quoted (literal) or double-quoted (with escape sequences) values.
+pub(crate) fn read_env_file(env_filepath: &PathBuf) -> BTreeMap<String, String> {
+    if !env_filepath.exists() || !env_filepath.is_file() {
+        eprintln!("The env file {} is not available", env_filepath.display());
+        exit(exitcode::NOINPUT)
+    }
+
+    let env_file_contents = match read_to_string(env_filepath) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!(
+                "Unable to read the env file {}\n\n{}\n",
+                env_filepath.display(),
+                e
+            );
+            exit(exitcode::IOERR)
+        }
+    };
+
+    let mut env_vars = BTreeMap::new();
+    for (line_index, line) in env_file_contents.lines().enumerate() {
+        let trimmed_line = line.trim();
+        if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
+            continue;
+        }
+
+        match parse_env_line(trimmed_line) {
+            Ok((var_name, var_value)) => {
+                env_vars.insert(var_name, var_value);
+            }
+            Err(reason) => {
+                eprintln!(
+                    "Malformed env file '{}', line {}:\n\t{}",
+                    env_filepath.display(),
+                    line_index + 1,
+                    reason
+                );
+                exit(exitcode::DATAERR)
+            }
+        }
+    }
+
+    env_vars
+}
+
+fn parse_env_line(line: &str) -> Result<(String, String), String> {
+    let line = match line.strip_prefix("export ") {
+        Some(l) => l.trim_start(),
+        None => line,
+    };
+
+    let (var_name, raw_value) = match line.find('=') {
+        Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+        None => return Err("expected a VAR_NAME=value assignment".to_string()),
+    };
+
+    let mut var_name_chars = var_name.chars();
+    let valid_name = match var_name_chars.next() {
+        Some(c) => {
+            (c.is_ascii_alphabetic() || c == '_')
+                && var_name_chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if !valid_name {
+        return Err(format!("invalid variable name '{}'", var_name));
+    }
+
+    let var_value = if let Some(quoted_value) = raw_value.strip_prefix('\'') {
+        match quoted_value.find('\'') {
+            Some(end) => check_trailing_chars(&quoted_value[end + 1..], &quoted_value[..end])?,
+            None => return Err("unterminated single-quoted value".to_string()),
+        }
+    } else if let Some(quoted_value) = raw_value.strip_prefix('"') {
+        let mut unescaped_value = String::with_capacity(quoted_value.len());
+        let mut chars = quoted_value.char_indices();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    end = Some(i);
+                    break;
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => unescaped_value.push('\n'),
+                    Some((_, 't')) => unescaped_value.push('\t'),
+                    Some((_, escaped_char)) => unescaped_value.push(escaped_char),
+                    None => break,
+                },
+                _ => unescaped_value.push(c),
+            }
+        }
+        match end {
+            Some(end) => check_trailing_chars(&quoted_value[end + 1..], &unescaped_value)?,
+            None => return Err("unterminated double-quoted value".to_string()),
+        }
+    } else {
+        match raw_value.find(" #") {
+            Some(comment_start) => raw_value[..comment_start].trim_end(),
+            None => raw_value,
+        }
+        .to_string()
+    };
+
+    Ok((var_name.to_string(), var_value))
+}
+
+fn check_trailing_chars(trailing_chars: &str, var_value: &str) -> Result<String, String> {
+    let trailing_chars = trailing_chars.trim();
+    if trailing_chars.is_empty() || trailing_chars.starts_with('#') {
+        Ok(var_value.to_string())
+    } else {
+        Err(format!(
+            "unexpected characters after quoted value: '{}'",
+            trailing_chars
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> (String, String) {
+        parse_env_line(line).unwrap()
+    }
+
+    #[test]
+    fn plain_assignments_are_parsed() {
+        assert_eq!(parse("FOO=bar"), ("FOO".to_string(), "bar".to_string()));
+        assert_eq!(parse("FOO = bar "), ("FOO".to_string(), "bar".to_string()));
+        assert_eq!(parse("FOO="), ("FOO".to_string(), "".to_string()));
+        assert_eq!(parse("FOO=a=b"), ("FOO".to_string(), "a=b".to_string()));
+        assert_eq!(
+            parse("export FOO=bar"),
+            ("FOO".to_string(), "bar".to_string())
+        );
+    }
+
+    #[test]
+    fn inline_comments_are_removed_from_unquoted_values() {
+        assert_eq!(parse("FOO=bar # comment").1, "bar");
+        assert_eq!(parse("FOO=bar#baz").1, "bar#baz");
+    }
+
+    #[test]
+    fn single_quoted_values_are_literal() {
+        assert_eq!(parse(r"FOO='a \n b'").1, r"a \n b");
+        assert_eq!(parse("FOO='a # b' # comment").1, "a # b");
+    }
+
+    #[test]
+    fn double_quoted_values_are_unescaped() {
+        assert_eq!(parse(r#"FOO="a\nb\tc""#).1, "a\nb\tc");
+        assert_eq!(parse(r#"FOO="say \"hi\"""#).1, "say \"hi\"");
+        assert_eq!(parse(r#"FOO="a # b" # comment"#).1, "a # b");
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert!(parse_env_line("FOO").is_err());
+        assert!(parse_env_line("=bar").is_err());
+        assert!(parse_env_line("1FOO=bar").is_err());
+        assert!(parse_env_line("FOO-BAR=baz").is_err());
+        assert!(parse_env_line("FOO='bar").is_err());
+        assert!(parse_env_line(r#"FOO="bar"#).is_err());
+        assert!(parse_env_line(r#"FOO="bar" baz"#).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use crate::directories::CONTAINER_HOME_PATH;
+
+pub(crate) const CONTAINER_HOME_VAR: &str = "AVATAR_CONTAINER_HOME";
+pub(crate) const HOST_PROJECT_DIR_VAR: &str = "AVATAR_HOST_PROJECT_DIR";
+pub(crate) const PROJECT_DIR_VAR: &str = "AVATAR_PROJECT_DIR";
+pub(crate) const PROJECT_ID_VAR: &str = "AVATAR_PROJECT_ID";
+
+/// Resolves `${VAR}`, `${VAR:-default}`, `${VAR-default}`, `${VAR:?message}`,
+/// `${VAR?message}` and leading `~` expressions. Values are resolved just before
+/// running the containers, so host secrets never end in the lock or state files.
+pub(crate) struct InterpolationContext {
+    avatar_vars: BTreeMap<&'static str, String>,
+    get_host_var: fn(&str) -> Option<String>, // replaced in tests
+    strict: bool, // undefined variables are errors, instead of empty strings
+}
+
+impl InterpolationContext {
+    pub fn new(
+        project_path: &Path,
+        container_project_path: &Path,
+        project_internal_id: &str,
+        strict: bool,
+    ) -> Self {
+        let mut avatar_vars = BTreeMap::new();
+        avatar_vars.insert(CONTAINER_HOME_VAR, CONTAINER_HOME_PATH.to_string());
+        avatar_vars.insert(
+            HOST_PROJECT_DIR_VAR,
+            project_path.to_string_lossy().to_string(),
+        );
+        avatar_vars.insert(
+            PROJECT_DIR_VAR,
+            container_project_path.to_string_lossy().to_string(),
+        );
+        avatar_vars.insert(PROJECT_ID_VAR, project_internal_id.to_string());
+
+        Self {
+            avatar_vars,
+            get_host_var: get_host_env_var,
+            strict,
+        }
+    }
+
+    /// Interpolates values that will be used inside the container, `~` points to
+    /// the container's home directory.
+    pub fn interpolate_container_value(&self, value: &str, setting_name: &str) -> String {
+        self.interpolate(value, CONTAINER_HOME_PATH, setting_name)
+    }
+
+    pub fn interpolate_container_path(&self, path: &Path, setting_name: &str) -> PathBuf {
+        PathBuf::from(self.interpolate_container_value(&path.to_string_lossy(), setting_name))
+    }
+
+    /// Interpolates paths that refer to the host filesystem, `~` points to the
+    /// user's home directory.
+    pub fn interpolate_host_path(&self, path: &Path, setting_name: &str) -> PathBuf {
+        let home_path = match dirs::home_dir() {
+            Some(p) => p.to_string_lossy().to_string(),
+            None => {
+                eprintln!("Unable to retrieve the user's home directory");
+                exit(exitcode::OSERR)
+            }
+        };
+
+        PathBuf::from(self.interpolate(&path.to_string_lossy(), &home_path, setting_name))
+    }
+
+    fn interpolate(&self, value: &str, home_path: &str, setting_name: &str) -> String {
+        let expanded_value = self.expand_vars(value, setting_name);
+
+        if value == "~" {
+            home_path.to_string()
+        } else if value.starts_with("~/") {
+            format!("{}{}", home_path, &expanded_value[1..])
+        } else {
+            expanded_value
+        }
+    }
+
+    fn expand_vars(&self, value: &str, setting_name: &str) -> String {
+        // Values without expressions are kept as they are, `$` included
+        if !value.contains("${") {
+            return value.to_string();
+        }
+
+        let chars: Vec<char> = value.chars().collect();
+        let mut expanded_value = String::with_capacity(value.len());
+
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == '$' && i + 1 < chars.len() {
+                // `$${` escapes an expression, other `$` characters are literal
+                if chars[i + 1] == '$' && i + 2 < chars.len() && chars[i + 2] == '{' {
+                    expanded_value.push_str("${");
+                    i += 3;
+                    continue;
+                }
+
+                if chars[i + 1] == '{' {
+                    let mut depth = 1;
+                    let mut j = i + 2;
+                    while j < chars.len() {
+                        if chars[j] == '{' && chars[j - 1] == '$' {
+                            depth += 1;
+                        } else if chars[j] == '}' {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        j += 1;
+                    }
+
+                    if j >= chars.len() {
+                        fail(setting_name, "unterminated variable expression");
+                    }
+
+                    let expression: String = chars[i + 2..j].iter().collect();
+                    expanded_value.push_str(&self.resolve_expression(&expression, setting_name));
+                    i = j + 1;
+                    continue;
+                }
+            }
+
+            expanded_value.push(chars[i]);
+            i += 1;
+        }
+
+        expanded_value
+    }
+
+    fn resolve_expression(&self, expression: &str, setting_name: &str) -> String {
+        let name_length = expression
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(expression.len());
+        let (var_name, modifier) = expression.split_at(name_length);
+
+        if var_name.is_empty() {
+            fail(
+                setting_name,
+                &format!("invalid variable expression '${{{}}}'", expression),
+            );
+        }
+
+        let var_value = match self.avatar_vars.get(var_name) {
+            Some(v) => Some(v.clone()),
+            None => (self.get_host_var)(var_name),
+        };
+
+        if modifier.is_empty() {
+            return match var_value {
+                Some(v) => v,
+                None if !self.strict => String::new(),
+                None => fail(
+                    setting_name,
+                    &format!(
+                        "the variable '{}' is not defined (use '${{{}:-}}' to allow empty values)",
+                        var_name, var_name
+                    ),
+                ),
+            };
+        }
+
+        let (check_empty, operator, operand) = if let Some(o) = modifier.strip_prefix(":-") {
+            (true, '-', o)
+        } else if let Some(o) = modifier.strip_prefix(":?") {
+            (true, '?', o)
+        } else if let Some(o) = modifier.strip_prefix('-') {
+            (false, '-', o)
+        } else if let Some(o) = modifier.strip_prefix('?') {
+            (false, '?', o)
+        } else {
+            fail(
+                setting_name,
+                &format!("invalid variable expression '${{{}}}'", expression),
+            )
+        };
+
+        match var_value {
+            Some(v) if !(check_empty && v.is_empty()) => v,
+            _ => match operator {
+                '-' => self.expand_vars(operand, setting_name),
+                _ => fail(
+                    setting_name,
+                    &format!(
+                        "the variable '{}' is required: {}",
+                        var_name,
+                        self.expand_vars(operand, setting_name)
+                    ),
+                ),
+            },
+        }
+    }
+}
+
+fn get_host_env_var(var_name: &str) -> Option<String> {
+    env::var(var_name).ok()
+}
+
+fn fail(setting_name: &str, reason: &str) -> ! {
+    eprintln!(
+        "Unable to interpolate the value of '{}': {}",
+        setting_name, reason
+    );
+    exit(exitcode::CONFIG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_context(strict: bool) -> InterpolationContext {
+        let mut context = InterpolationContext::new(
+            &PathBuf::from("/home/user/project"),
+            Path::new("/playground"),
+            "prjid",
+            strict,
+        );
+        // The process environment is shared by the tests running in parallel
+        context.get_host_var = |var_name| match var_name {
+            "HOST_VAR" => Some("value".to_string()),
+            "HOST_EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        context
+    }
+
+    #[test]
+    fn values_without_expressions_are_unchanged() {
+        let context = get_context(true);
+        for value in &["plain", "$HOME", "a$$b", "$", "cost: 5$", "$$"] {
+            assert_eq!(context.expand_vars(value, "test"), *value);
+        }
+    }
+
+    #[test]
+    fn avatar_vars_are_expanded() {
+        let context = get_context(true);
+        assert_eq!(
+            context.expand_vars("${AVATAR_PROJECT_DIR}/.go", "test"),
+            "/playground/.go"
+        );
+        assert_eq!(
+            context.expand_vars("${AVATAR_HOST_PROJECT_DIR}:${AVATAR_PROJECT_ID}", "test"),
+            "/home/user/project:prjid"
+        );
+    }
+
+    #[test]
+    fn host_vars_are_expanded() {
+        let context = get_context(true);
+        assert_eq!(context.expand_vars("x${HOST_VAR}x", "test"), "xvaluex");
+        assert_eq!(context.expand_vars("${HOST_EMPTY}", "test"), "");
+    }
+
+    #[test]
+    fn default_values_are_used_for_undefined_vars() {
+        let context = get_context(true);
+
+        assert_eq!(
+            context.expand_vars("${UNDEFINED:-fallback}", "test"),
+            "fallback"
+        );
+        assert_eq!(
+            context.expand_vars("${UNDEFINED-fallback}", "test"),
+            "fallback"
+        );
+        assert_eq!(
+            context.expand_vars("${HOST_EMPTY:-fallback}", "test"),
+            "fallback"
+        );
+        assert_eq!(context.expand_vars("${HOST_EMPTY-fallback}", "test"), "");
+        assert_eq!(context.expand_vars("${UNDEFINED:-}", "test"), "");
+        assert_eq!(
+            context.expand_vars("${UNDEFINED:-${AVATAR_PROJECT_ID}}", "test"),
+            "prjid"
+        );
+    }
+
+    #[test]
+    fn undefined_vars_are_empty_in_non_strict_mode() {
+        let context = get_context(false);
+        assert_eq!(context.expand_vars("a${UNDEFINED}b", "test"), "ab");
+    }
+
+    #[test]
+    fn escaped_expressions_are_not_expanded() {
+        let context = get_context(true);
+        assert_eq!(
+            context.expand_vars("$${AVATAR_PROJECT_ID}", "test"),
+            "${AVATAR_PROJECT_ID}"
+        );
+        assert_eq!(
+            context.expand_vars("$$${AVATAR_PROJECT_ID}", "test"),
+            "$${AVATAR_PROJECT_ID}"
+        );
+        assert_eq!(
+            context.expand_vars("$$ ${AVATAR_PROJECT_ID}", "test"),
+            "$$ prjid"
+        );
+    }
+
+    #[test]
+    fn leading_tilde_points_to_the_home_directory() {
+        let context = get_context(true);
+        assert_eq!(
+            context.interpolate_container_value("~/.npm", "test"),
+            "/home/avatar-cli/.npm"
+        );
+        assert_eq!(
+            context.interpolate_container_value("a/~/b", "test"),
+            "a/~/b"
+        );
+    }
+}
@@ -6,8 +6,15 @@
 
 mod avatar_env;
 mod directories;
+mod dotenv;
+mod interpolation;
 mod project_config;
+mod run_index;
 mod subcommands;
+mod supervisor;
+#[cfg(test)]
+mod test_helpers;
+mod warm_containers;
 
 fn main() {
     let used_program_name = avatar_env::get_used_program_name();
@@ -5,17 +5,19 @@
  */
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::fs::{read, write};
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::process::exit;
+use std::fs::{canonicalize, read, read_dir, remove_file, rename, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, exit};
 use std::vec::Vec;
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use ring::digest::{digest, Digest, SHA256};
+use ring::digest::{digest, Context, Digest, SHA256};
 use serde::{Deserialize, Serialize};
 
+use crate::directories::get_user_config_path;
 use crate::subcommands::AVATAR_CLI_VERSION;
+use crate::warm_containers::is_process_alive;
 
 // Structs, Enums & their Impl blocks:
 // -----------------------------------------------------------------------------
@@ -37,11 +39,128 @@ impl ImageBinaryConfig {
     }
 }
 
+/// Host environment variables passed to the containers. They can be specified
+/// by name, by pattern (e.g. `AWS_*`), or through a mapping that allows to
+/// rename them and to set a default value.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum EnvFromHostConfig {
+    Name(String),
+    Mapping(EnvFromHostMapping),
+}
+
+impl EnvFromHostConfig {
+    /// Container variable name (or pattern) that identifies the entry
+    pub fn get_name(&self) -> &String {
+        match self {
+            EnvFromHostConfig::Name(name) => name,
+            EnvFromHostConfig::Mapping(mapping) => &mapping.name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EnvFromHostMapping {
+    name: String,
+    from: Option<String>,
+    default: Option<String>,
+}
+
+impl EnvFromHostMapping {
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    /// Host variable name, it defaults to the container variable name
+    pub fn get_from(&self) -> &String {
+        match &self.from {
+            Some(from) => from,
+            None => &self.name,
+        }
+    }
+
+    pub fn get_default(&self) -> &Option<String> {
+        &self.default
+    }
+}
+
+/// Host paths bound into the containers. A plain path is a shorthand for a
+/// writable binding without extra options.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum BindingConfig {
+    Path(PathBuf),
+    Options(BindingOptions),
+}
+
+impl BindingConfig {
+    /// Host path, relative paths are resolved against the project directory
+    pub fn get_source(&self) -> &PathBuf {
+        match self {
+            BindingConfig::Path(source) => source,
+            BindingConfig::Options(options) => &options.source,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            BindingConfig::Path(_) => false,
+            BindingConfig::Options(options) => options.read_only,
+        }
+    }
+
+    pub fn must_create(&self) -> bool {
+        match self {
+            BindingConfig::Path(_) => false,
+            BindingConfig::Options(options) => options.create,
+        }
+    }
+
+    pub fn get_selinux_label(&self) -> Option<&SELinuxLabel> {
+        match self {
+            BindingConfig::Path(_) => None,
+            BindingConfig::Options(options) => options.selinux.as_ref(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BindingOptions {
+    source: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    create: bool, // creates the host directory if it does not exist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selinux: Option<SELinuxLabel>,
+}
+
+/// SELinux relabelling of bind mounts: `Shared` (`z`) allows the content to be
+/// used by many containers, `Private` (`Z`) restricts it to a single one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum SELinuxLabel {
+    Shared,
+    Private,
+}
+
+impl SELinuxLabel {
+    pub fn get_mount_option(&self) -> &str {
+        match self {
+            SELinuxLabel::Shared => "z",
+            SELinuxLabel::Private => "Z",
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ImageBinaryConfigLock {
     oci_image_name: String,
     oci_image_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oci_image_env_path: Option<String>,
     path: PathBuf,
     run_config: Option<OCIContainerRunConfigLock>,
 }
@@ -50,12 +169,14 @@ impl ImageBinaryConfigLock {
     pub fn new(
         oci_image_name: String,
         oci_image_hash: String,
+        oci_image_env_path: Option<String>,
         path: PathBuf,
         run_config: Option<OCIContainerRunConfigLock>,
     ) -> ImageBinaryConfigLock {
         ImageBinaryConfigLock {
             oci_image_name,
             oci_image_hash,
+            oci_image_env_path,
             path,
             run_config,
         }
@@ -69,6 +190,40 @@ impl ImageBinaryConfigLock {
         &self.oci_image_hash
     }
 
+    pub fn get_oci_image_env_path(&self) -> &Option<String> {
+        &self.oci_image_env_path
+    }
+
+    pub fn get_workdir_policy(&self) -> WorkdirPolicy {
+        let workdir = match &self.run_config {
+            Some(run_config) => run_config.get_workdir().clone(),
+            None => None,
+        };
+
+        match parse_workdir_policy(&workdir) {
+            Some(workdir_policy) => workdir_policy,
+            None => {
+                eprintln!("Invalid workdir setting: '{}'", workdir.unwrap_or_default());
+                exit(exitcode::DATAERR)
+            }
+        }
+    }
+
+    pub fn get_container_user(&self) -> ContainerUser {
+        let user = match &self.run_config {
+            Some(run_config) => run_config.get_user().clone(),
+            None => None,
+        };
+
+        match parse_container_user(&user) {
+            Some(container_user) => container_user,
+            None => {
+                eprintln!("Invalid user setting: '{}'", user.unwrap_or_default());
+                exit(exitcode::DATAERR)
+            }
+        }
+    }
+
     pub fn get_path(&self) -> &PathBuf {
         &self.path
     }
@@ -82,9 +237,49 @@ impl ImageBinaryConfigLock {
 #[serde(rename_all = "camelCase")]
 pub(crate) struct OCIContainerRunConfig {
     env: Option<BTreeMap<String, String>>,
-    env_from_host: Option<BTreeSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env_file: Option<Vec<PathBuf>>, // dotenv files, relative to the project directory
+    env_from_host: Option<BTreeSet<EnvFromHostConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_prepend: Option<Vec<String>>, // directories added before the image's PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_append: Option<Vec<String>>, // directories added after the image's PATH
     volumes: Option<BTreeMap<PathBuf, VolumeConfig>>, // container path -> volume config
-    bindings: Option<BTreeMap<PathBuf, PathBuf>>,     // container path -> host path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tmpfs: Option<BTreeMap<PathBuf, TmpfsConfig>>, // container path -> tmpfs config
+    bindings: Option<BTreeMap<PathBuf, BindingConfig>>, // container path -> host path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_mount: Option<ProjectMount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supervised: Option<bool>, // runs the container under avatar's supervision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persistent: Option<bool>, // reuses one container per session, through `docker exec`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interactive: Option<InteractivityMode>, // keeps stdin open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tty: Option<InteractivityMode>, // allocates a pseudo-TTY
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workdir: Option<String>, // cwd (default), project-root, or a project-relative path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translate_arg_paths: Option<bool>, // rewrites host paths passed as arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict_interpolation: Option<bool>, // fails on undefined variables (default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selinux_relabel: Option<bool>, // relabels the implicit project & home mounts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resources: Option<ResourcesConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security: Option<SecurityConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>, // host (default), root, image, or uid:gid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<BTreeSet<String>>, // supplementary groups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>, // bridge, none, host or the name of a network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<String>>, // [host_ip:](host_port|auto):container_port[/protocol]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unset: Option<OCIContainerRunConfigUnset>, // settings removed from the inherited config
 }
 
 impl OCIContainerRunConfig {
@@ -92,26 +287,144 @@ impl OCIContainerRunConfig {
         &self.env
     }
 
-    pub fn get_env_from_host(&self) -> &Option<BTreeSet<String>> {
+    pub fn get_env_file(&self) -> &Option<Vec<PathBuf>> {
+        &self.env_file
+    }
+
+    pub fn get_env_from_host(&self) -> &Option<BTreeSet<EnvFromHostConfig>> {
         &self.env_from_host
     }
 
+    pub fn get_path_prepend(&self) -> &Option<Vec<String>> {
+        &self.path_prepend
+    }
+
+    pub fn get_path_append(&self) -> &Option<Vec<String>> {
+        &self.path_append
+    }
+
     pub fn get_volumes(&self) -> &Option<BTreeMap<PathBuf, VolumeConfig>> {
         &self.volumes
     }
 
-    pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, PathBuf>> {
+    pub fn get_tmpfs(&self) -> &Option<BTreeMap<PathBuf, TmpfsConfig>> {
+        &self.tmpfs
+    }
+
+    pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, BindingConfig>> {
         &self.bindings
     }
+
+    pub fn get_project_mount(&self) -> &Option<ProjectMount> {
+        &self.project_mount
+    }
+
+    pub fn get_supervised(&self) -> &Option<bool> {
+        &self.supervised
+    }
+
+    pub fn get_persistent(&self) -> &Option<bool> {
+        &self.persistent
+    }
+
+    pub fn get_interactive(&self) -> &Option<InteractivityMode> {
+        &self.interactive
+    }
+
+    pub fn get_tty(&self) -> &Option<InteractivityMode> {
+        &self.tty
+    }
+
+    pub fn get_workdir(&self) -> &Option<String> {
+        &self.workdir
+    }
+
+    pub fn get_translate_arg_paths(&self) -> &Option<bool> {
+        &self.translate_arg_paths
+    }
+
+    pub fn get_strict_interpolation(&self) -> &Option<bool> {
+        &self.strict_interpolation
+    }
+
+    pub fn get_selinux_relabel(&self) -> &Option<bool> {
+        &self.selinux_relabel
+    }
+
+    pub fn get_resources(&self) -> &Option<ResourcesConfig> {
+        &self.resources
+    }
+
+    pub fn get_security(&self) -> &Option<SecurityConfig> {
+        &self.security
+    }
+
+    pub fn get_user(&self) -> &Option<String> {
+        &self.user
+    }
+
+    pub fn get_groups(&self) -> &Option<BTreeSet<String>> {
+        &self.groups
+    }
+
+    pub fn get_network(&self) -> &Option<String> {
+        &self.network
+    }
+
+    pub fn get_ports(&self) -> &Option<Vec<String>> {
+        &self.ports
+    }
+
+    pub fn get_unset(&self) -> &Option<OCIContainerRunConfigUnset> {
+        &self.unset
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct OCIContainerRunConfigLock {
     env: Option<BTreeMap<String, String>>,
-    env_from_host: Option<BTreeSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env_file: Option<Vec<PathBuf>>,
+    env_from_host: Option<BTreeSet<EnvFromHostConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_prepend: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_append: Option<Vec<String>>,
     volumes: Option<Vec<VolumeConfigLock>>,
-    bindings: Option<BTreeMap<PathBuf, PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tmpfs: Option<BTreeMap<PathBuf, TmpfsConfig>>,
+    bindings: Option<BTreeMap<PathBuf, BindingConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_mount: Option<ProjectMount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supervised: Option<bool>, // runs the container under avatar's supervision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persistent: Option<bool>, // reuses one container per session, through `docker exec`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interactive: Option<InteractivityMode>, // keeps stdin open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tty: Option<InteractivityMode>, // allocates a pseudo-TTY
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workdir: Option<String>, // cwd (default), project-root, or a project-relative path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translate_arg_paths: Option<bool>, // rewrites host paths passed as arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict_interpolation: Option<bool>, // fails on undefined variables (default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selinux_relabel: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resources: Option<ResourcesConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security: Option<SecurityConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>, // host (default), root, image, or uid:gid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<BTreeSet<String>>, // supplementary groups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>, // bridge, none, host or the name of a network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<String>>, // [host_ip:](host_port|auto):container_port[/protocol]
 }
 
 impl OCIContainerRunConfigLock {
@@ -119,17 +432,157 @@ impl OCIContainerRunConfigLock {
         &self.env
     }
 
-    pub fn get_env_from_host(&self) -> &Option<BTreeSet<String>> {
+    pub fn get_env_file(&self) -> &Option<Vec<PathBuf>> {
+        &self.env_file
+    }
+
+    pub fn get_env_from_host(&self) -> &Option<BTreeSet<EnvFromHostConfig>> {
         &self.env_from_host
     }
 
+    pub fn get_path_prepend(&self) -> &Option<Vec<String>> {
+        &self.path_prepend
+    }
+
+    pub fn get_path_append(&self) -> &Option<Vec<String>> {
+        &self.path_append
+    }
+
     pub fn get_volumes(&self) -> &Option<Vec<VolumeConfigLock>> {
         &self.volumes
     }
 
-    pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, PathBuf>> {
+    pub fn get_tmpfs(&self) -> &Option<BTreeMap<PathBuf, TmpfsConfig>> {
+        &self.tmpfs
+    }
+
+    pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, BindingConfig>> {
+        &self.bindings
+    }
+
+    pub fn get_project_mount(&self) -> &Option<ProjectMount> {
+        &self.project_mount
+    }
+
+    pub fn get_supervised(&self) -> &Option<bool> {
+        &self.supervised
+    }
+
+    pub fn get_persistent(&self) -> &Option<bool> {
+        &self.persistent
+    }
+
+    pub fn get_interactive(&self) -> &Option<InteractivityMode> {
+        &self.interactive
+    }
+
+    pub fn get_tty(&self) -> &Option<InteractivityMode> {
+        &self.tty
+    }
+
+    pub fn get_workdir(&self) -> &Option<String> {
+        &self.workdir
+    }
+
+    pub fn get_translate_arg_paths(&self) -> &Option<bool> {
+        &self.translate_arg_paths
+    }
+
+    pub fn get_strict_interpolation(&self) -> &Option<bool> {
+        &self.strict_interpolation
+    }
+
+    pub fn get_selinux_relabel(&self) -> &Option<bool> {
+        &self.selinux_relabel
+    }
+
+    pub fn get_resources(&self) -> &Option<ResourcesConfig> {
+        &self.resources
+    }
+
+    pub fn get_security(&self) -> &Option<SecurityConfig> {
+        &self.security
+    }
+
+    pub fn get_user(&self) -> &Option<String> {
+        &self.user
+    }
+
+    pub fn get_groups(&self) -> &Option<BTreeSet<String>> {
+        &self.groups
+    }
+
+    pub fn get_network(&self) -> &Option<String> {
+        &self.network
+    }
+
+    pub fn get_ports(&self) -> &Option<Vec<String>> {
+        &self.ports
+    }
+}
+
+/// Lists the inherited settings that have to be removed before applying the ones
+/// defined in the same `runConfig` block.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OCIContainerRunConfigUnset {
+    env: Option<BTreeSet<String>>,
+    env_file: Option<BTreeSet<PathBuf>>,
+    env_from_host: Option<BTreeSet<String>>,
+    path_prepend: Option<BTreeSet<String>>,
+    path_append: Option<BTreeSet<String>>,
+    volumes: Option<BTreeSet<PathBuf>>,
+    tmpfs: Option<BTreeSet<PathBuf>>,
+    ports: Option<BTreeSet<String>>,
+    groups: Option<BTreeSet<String>>,
+    bindings: Option<BTreeSet<PathBuf>>,
+    ulimits: Option<BTreeSet<String>>, // resources.ulimits
+}
+
+impl OCIContainerRunConfigUnset {
+    pub fn get_env(&self) -> &Option<BTreeSet<String>> {
+        &self.env
+    }
+
+    pub fn get_env_file(&self) -> &Option<BTreeSet<PathBuf>> {
+        &self.env_file
+    }
+
+    pub fn get_env_from_host(&self) -> &Option<BTreeSet<String>> {
+        &self.env_from_host
+    }
+
+    pub fn get_path_prepend(&self) -> &Option<BTreeSet<String>> {
+        &self.path_prepend
+    }
+
+    pub fn get_path_append(&self) -> &Option<BTreeSet<String>> {
+        &self.path_append
+    }
+
+    pub fn get_volumes(&self) -> &Option<BTreeSet<PathBuf>> {
+        &self.volumes
+    }
+
+    pub fn get_tmpfs(&self) -> &Option<BTreeSet<PathBuf>> {
+        &self.tmpfs
+    }
+
+    pub fn get_ports(&self) -> &Option<BTreeSet<String>> {
+        &self.ports
+    }
+
+    pub fn get_groups(&self) -> &Option<BTreeSet<String>> {
+        &self.groups
+    }
+
+    pub fn get_bindings(&self) -> &Option<BTreeSet<PathBuf>> {
         &self.bindings
     }
+
+    pub fn get_ulimits(&self) -> &Option<BTreeSet<String>> {
+        &self.ulimits
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -154,28 +607,58 @@ impl OCIImageConfig {
 pub(crate) struct OCIImageConfigLock {
     hash: String,
     run_config: Option<OCIContainerRunConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env_path: Option<String>, // PATH defined in the image config
 }
 
 impl OCIImageConfigLock {
-    pub fn new(hash: String, run_config: Option<OCIContainerRunConfig>) -> OCIImageConfigLock {
-        OCIImageConfigLock { hash, run_config }
+    pub fn new(
+        hash: String,
+        run_config: Option<OCIContainerRunConfig>,
+        env_path: Option<String>,
+    ) -> OCIImageConfigLock {
+        OCIImageConfigLock {
+            hash,
+            run_config,
+            env_path,
+        }
     }
 
     pub fn get_hash(&self) -> &String {
         &self.hash
     }
 
+    pub fn get_env_path(&self) -> &Option<String> {
+        &self.env_path
+    }
+
     pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
         &self.run_config
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Named overlay, selected per session, applied on top of the project config.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProfileConfig {
+    run_config: Option<OCIContainerRunConfig>,
+    images: Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ProjectConfig {
     version: String,
     project_internal_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extends: Option<Vec<PathBuf>>, // shared config files, merged below this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_config: Option<OCIContainerRunConfig>, // defaults applied to every image
     images: Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>, // image name -> image tag -> oci image config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profiles: Option<BTreeMap<String, ProfileConfig>>, // profile name -> config overlay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_refresh: Option<bool>, // regenerates outdated lock & state files from the sessions
 }
 
 impl ProjectConfig {
@@ -185,19 +668,42 @@ impl ProjectConfig {
         ProjectConfig {
             version: AVATAR_CLI_VERSION.to_string(),
             project_internal_id: prj_internal_id,
+            extends: None,
+            run_config: None,
             images: None,
+            profiles: None,
+            auto_refresh: None,
         }
     }
 
+    pub fn get_auto_refresh(&self) -> &Option<bool> {
+        &self.auto_refresh
+    }
+
     pub fn get_project_internal_id(&self) -> &String {
         &self.project_internal_id
     }
 
+    pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
+        &self.run_config
+    }
+
     pub fn get_images(&self) -> &Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>> {
         &self.images
     }
 }
 
+/// Shared configuration file, included through the `extends` property. It can't
+/// define the project's version nor its internal id.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProjectConfigFragment {
+    extends: Option<Vec<PathBuf>>,
+    run_config: Option<OCIContainerRunConfig>,
+    images: Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>,
+    profiles: Option<BTreeMap<String, ProfileConfig>>,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ProjectConfigLock {
@@ -206,6 +712,10 @@ pub(crate) struct ProjectConfigLock {
     project_internal_id: String,
     images: BTreeMap<String, BTreeMap<String, OCIImageConfigLock>>, // image_name -> image_tag -> image config & hash
     binaries: BTreeMap<String, ImageBinaryConfigLock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_config_hash: Option<String>, // only set in the project state file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>, // only set in the project state file
 }
 
 impl ProjectConfigLock {
@@ -218,6 +728,24 @@ impl ProjectConfigLock {
         self
     }
 
+    pub fn get_local_config_hash(&self) -> &Option<String> {
+        &self.local_config_hash
+    }
+
+    pub fn update_local_config_hash(mut self, new_hash: Option<&[u8]>) -> ProjectConfigLock {
+        self.local_config_hash = new_hash.map(hex::encode);
+        self
+    }
+
+    pub fn get_profile(&self) -> &Option<String> {
+        &self.profile
+    }
+
+    pub fn update_profile(mut self, profile: Option<&str>) -> ProjectConfigLock {
+        self.profile = profile.map(String::from);
+        self
+    }
+
     pub fn get_project_internal_id(&self) -> &String {
         &self.project_internal_id
     }
@@ -226,10 +754,6 @@ impl ProjectConfigLock {
         &self.images
     }
 
-    pub fn get_binary_configuration(&self, binary_name: &str) -> Option<&ImageBinaryConfigLock> {
-        self.binaries.get(binary_name)
-    }
-
     pub fn get_binary_names(
         &self,
     ) -> std::collections::btree_map::Keys<'_, std::string::String, ImageBinaryConfigLock> {
@@ -253,64 +777,290 @@ impl ProjectConfigLock {
             project_internal_id,
             images,
             binaries,
+            local_config_hash: None,
+            profile: None,
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Limits applied to the containers' resources. Each field is merged
+/// separately, so a binary can override a single limit set at image level.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct VolumeConfig {
-    name: Option<String>,
-    #[serde(default = "VolumeScope::default")]
-    scope: VolumeScope,
+pub(crate) struct ResourcesConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory: Option<String>, // e.g. 2g
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_swap: Option<String>, // memory + swap, or -1 for unlimited swap
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpus: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pids_limit: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ulimits: Option<BTreeMap<String, UlimitConfig>>, // ulimit name -> limits
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct VolumeConfigLock {
-    container_path: PathBuf,
-    volume_name: String,
-}
+impl ResourcesConfig {
+    pub fn get_memory(&self) -> &Option<String> {
+        &self.memory
+    }
 
-impl VolumeConfigLock {
-    pub fn get_container_path(&self) -> &PathBuf {
-        &self.container_path
+    pub fn get_memory_swap(&self) -> &Option<String> {
+        &self.memory_swap
     }
 
-    pub fn get_name(&self) -> &String {
-        &self.volume_name
+    pub fn get_cpus(&self) -> &Option<f64> {
+        &self.cpus
+    }
+
+    pub fn get_pids_limit(&self) -> &Option<i64> {
+        &self.pids_limit
+    }
+
+    pub fn get_ulimits(&self) -> &Option<BTreeMap<String, UlimitConfig>> {
+        &self.ulimits
     }
 }
 
+/// A single value sets both the soft and the hard limits
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-enum VolumeScope {
-    Project,
-    OCIImage,
-    Binary,
+#[serde(untagged)]
+pub(crate) enum UlimitConfig {
+    Value(i64),
+    Range { soft: i64, hard: i64 },
 }
 
-impl VolumeScope {
-    fn default() -> Self {
-        VolumeScope::Project
+impl UlimitConfig {
+    pub fn get_soft(&self) -> i64 {
+        match self {
+            UlimitConfig::Value(value) => *value,
+            UlimitConfig::Range { soft, .. } => *soft,
+        }
+    }
+
+    pub fn get_hard(&self) -> i64 {
+        match self {
+            UlimitConfig::Value(value) => *value,
+            UlimitConfig::Range { hard, .. } => *hard,
+        }
     }
 }
 
-// Functions:
-// -----------------------------------------------------------------------------
+/// Hardening options for the containers. Explicit settings take precedence over
+/// the ones implied by the preset, which is expanded when generating the lock.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SecurityConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preset: Option<SecurityPreset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap_drop: Option<BTreeSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap_add: Option<BTreeSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_new_privileges: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_only_root_filesystem: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seccomp_profile: Option<PathBuf>, // relative to the project directory, or "unconfined"
+}
 
-fn generate_volume_config_lock(
-    image_volume_configs: &Option<BTreeMap<PathBuf, VolumeConfig>>,
-    project_internal_id: &str,
-    image_ref: &str,
-    binary_name: &str,
-) -> Option<Vec<VolumeConfigLock>> {
-    match image_volume_configs {
-        Some(_src_volume_config) => Some(
-            _src_volume_config
-                .iter()
-                .map(|(container_path, volume_config)| VolumeConfigLock {
-                    container_path: container_path.clone(),
-                    volume_name: generate_volume_name(
+impl SecurityConfig {
+    pub fn get_preset(&self) -> &Option<SecurityPreset> {
+        &self.preset
+    }
+
+    pub fn get_cap_drop(&self) -> &Option<BTreeSet<String>> {
+        &self.cap_drop
+    }
+
+    pub fn get_cap_add(&self) -> &Option<BTreeSet<String>> {
+        &self.cap_add
+    }
+
+    pub fn get_no_new_privileges(&self) -> &Option<bool> {
+        &self.no_new_privileges
+    }
+
+    pub fn get_read_only_root_filesystem(&self) -> &Option<bool> {
+        &self.read_only_root_filesystem
+    }
+
+    pub fn get_seccomp_profile(&self) -> &Option<PathBuf> {
+        &self.seccomp_profile
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum SecurityPreset {
+    Default, // Allows to disable a preset inherited from lower precedence levels
+    Strict,  // Drops all capabilities, forbids privilege escalation & makes the rootfs read-only
+}
+
+/// Where the project directory is mounted inside the containers
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ProjectMount {
+    Playground, // /playground
+    HostPath,   // Same absolute path as in the host
+}
+
+/// `auto` decides depending on the state of the standard streams
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InteractivityMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl InteractivityMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(InteractivityMode::Auto),
+            "always" => Some(InteractivityMode::Always),
+            "never" => Some(InteractivityMode::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Directory where the tools are executed
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum WorkdirPolicy {
+    Cwd, // Same directory as in the host, relative to the project
+    ProjectRoot,
+    Subdir(PathBuf), // Relative to the project directory
+}
+
+/// User running the tools inside the containers
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ContainerUser {
+    Host,  // Same uid & gid as the host user, with a generated passwd entry
+    Root,  // uid 0, with the image's passwd file
+    Image, // Whatever user is defined in the image config
+    Id(u32, u32),
+}
+
+/// Published port, a missing host port means that it will be assigned when the
+/// container starts.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PortMapping {
+    host_ip: Option<String>,
+    host_port: Option<u16>,
+    container_port: u16,
+    protocol: String,
+}
+
+impl PortMapping {
+    pub fn get_host_ip(&self) -> &Option<String> {
+        &self.host_ip
+    }
+
+    pub fn get_host_port(&self) -> &Option<u16> {
+        &self.host_port
+    }
+
+    pub fn get_container_port(&self) -> u16 {
+        self.container_port
+    }
+
+    pub fn get_protocol(&self) -> &String {
+        &self.protocol
+    }
+}
+
+const ULIMIT_NAMES: [&str; 15] = [
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VolumeConfig {
+    name: Option<String>,
+    #[serde(default = "VolumeScope::default")]
+    scope: VolumeScope,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TmpfsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<String>, // e.g. 64m
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>, // octal, e.g. "1777"
+}
+
+impl TmpfsConfig {
+    pub fn get_size(&self) -> &Option<String> {
+        &self.size
+    }
+
+    pub fn get_mode(&self) -> &Option<String> {
+        &self.mode
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VolumeConfigLock {
+    container_path: PathBuf,
+    volume_name: String,
+}
+
+impl VolumeConfigLock {
+    pub fn get_container_path(&self) -> &PathBuf {
+        &self.container_path
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.volume_name
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum VolumeScope {
+    Project,
+    OCIImage,
+    Binary,
+}
+
+impl VolumeScope {
+    fn default() -> Self {
+        VolumeScope::Project
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+fn generate_volume_config_lock(
+    image_volume_configs: &Option<BTreeMap<PathBuf, VolumeConfig>>,
+    project_internal_id: &str,
+    image_ref: &str,
+    binary_name: &str,
+) -> Option<Vec<VolumeConfigLock>> {
+    match image_volume_configs {
+        Some(_src_volume_config) => Some(
+            _src_volume_config
+                .iter()
+                .map(|(container_path, volume_config)| VolumeConfigLock {
+                    container_path: container_path.clone(),
+                    volume_name: generate_volume_name(
                         project_internal_id,
                         image_ref,
                         binary_name,
@@ -329,7 +1079,7 @@ fn generate_volume_name(
     image_ref: &str,
     binary_name: &str,
     volume_config: &VolumeConfig,
-    container_path: &PathBuf,
+    container_path: &Path,
 ) -> String {
     match &volume_config.name {
         Some(volume_name) => volume_name.clone(),
@@ -364,64 +1114,224 @@ fn generate_volume_name(
     }
 }
 
-pub(crate) fn get_config(config_filepath: &PathBuf) -> (ProjectConfig, Digest) {
+/// Loads the project config, merging the shared config files declared in its
+/// `extends` property. The returned digest covers the contents of all of them,
+/// and their resolved paths are appended to `included_paths`, along with the
+/// candidate paths that were checked before (and didn't exist), as creating
+/// one of them changes the resolution.
+pub(crate) fn get_config(
+    config_filepath: &PathBuf,
+    included_paths: &mut Vec<PathBuf>,
+) -> (ProjectConfig, Digest) {
     let config_bytes = get_file_bytes(config_filepath);
 
-    (
-        match serde_yaml::from_slice::<ProjectConfig>(&config_bytes) {
-            Ok(_config) => _config,
-            Err(e) => {
-                let error_msg = match e.location() {
-                    Some(l) => format!(
-                        "Malformed config file '{}', line {}, column {}:\n\t{}",
-                        config_filepath.display(),
-                        l.line(),
-                        l.column(),
-                        e.to_string(),
-                    ),
-                    None => format!(
-                        "Malformed config file '{}':\n\t{}",
-                        config_filepath.display(),
-                        e.to_string(),
-                    ),
-                };
+    let mut config = match serde_yaml::from_slice::<ProjectConfig>(&config_bytes) {
+        Ok(_config) => _config,
+        Err(e) => {
+            let error_msg = match e.location() {
+                Some(l) => format!(
+                    "Malformed config file '{}', line {}, column {}:\n\t{}",
+                    config_filepath.display(),
+                    l.line(),
+                    l.column(),
+                    e,
+                ),
+                None => format!(
+                    "Malformed config file '{}':\n\t{}",
+                    config_filepath.display(),
+                    e,
+                ),
+            };
 
-                eprintln!("{}", error_msg);
-                exit(exitcode::DATAERR)
-            }
-        },
-        digest(&SHA256, &config_bytes),
-    )
+            eprintln!("{}", error_msg);
+            exit(exitcode::DATAERR)
+        }
+    };
+
+    let mut config_hash_context = Context::new(&SHA256);
+    config_hash_context.update(&config_bytes);
+
+    if let Some(extends) = config.extends.take() {
+        let base_config = get_config_fragments(
+            &extends,
+            get_project_path_from_config_path(config_filepath),
+            &mut config_hash_context,
+            &mut vec![get_canonical_path(config_filepath)],
+            included_paths,
+        );
+
+        config.run_config = merge_run_config_layers(&base_config.run_config, &config.run_config);
+        config.images = merge_images(&base_config.images, &config.images);
+        config.profiles = merge_profiles(&base_config.profiles, &config.profiles);
+    }
+
+    (config, config_hash_context.finish())
 }
 
-pub(crate) fn get_config_lock(config_lock_filepath: &PathBuf) -> (ProjectConfigLock, Digest) {
-    let config_lock_bytes = get_file_bytes(config_lock_filepath);
+/// Loads the personal (and uncommitted) config overrides, if they exist.
+pub(crate) fn get_local_config(
+    local_config_filepath: &PathBuf,
+    included_paths: &mut Vec<PathBuf>,
+) -> Option<(ProjectConfigFragment, Digest)> {
+    if !local_config_filepath.exists() {
+        return None;
+    }
 
-    (
-        match serde_yaml::from_slice::<ProjectConfigLock>(&config_lock_bytes) {
-            Ok(_config_lock) => _config_lock,
-            Err(e) => {
-                let error_msg = match e.location() {
-                    Some(l) => format!(
-                        "Malformed lock file '{}', line {}, column {}:\n\t{}",
-                        config_lock_filepath.display(),
-                        l.line(),
-                        l.column(),
-                        e.to_string(),
-                    ),
-                    None => format!(
-                        "Malformed lock file '{}':\n\t{}",
-                        config_lock_filepath.display(),
-                        e.to_string(),
-                    ),
-                };
+    let local_config_bytes = get_file_bytes(local_config_filepath);
+    let mut local_config = get_config_fragment(local_config_filepath, &local_config_bytes);
+
+    let mut local_config_hash_context = Context::new(&SHA256);
+    local_config_hash_context.update(&local_config_bytes);
+
+    if let Some(extends) = local_config.extends.take() {
+        let base_config = get_config_fragments(
+            &extends,
+            get_project_path_from_config_path(local_config_filepath),
+            &mut local_config_hash_context,
+            &mut vec![get_canonical_path(local_config_filepath)],
+            included_paths,
+        );
+        local_config = merge_config_fragments(&base_config, &local_config);
+    }
 
-                eprintln!("{}", error_msg);
-                exit(exitcode::DATAERR)
-            }
-        },
-        digest(&SHA256, &config_lock_bytes),
-    )
+    Some((local_config, local_config_hash_context.finish()))
+}
+
+fn get_config_fragment(fragment_filepath: &Path, fragment_bytes: &[u8]) -> ProjectConfigFragment {
+    match serde_yaml::from_slice::<ProjectConfigFragment>(fragment_bytes) {
+        Ok(_fragment) => _fragment,
+        Err(e) => {
+            let error_msg = match e.location() {
+                Some(l) => format!(
+                    "Malformed config file '{}', line {}, column {}:\n\t{}",
+                    fragment_filepath.display(),
+                    l.line(),
+                    l.column(),
+                    e,
+                ),
+                None => format!(
+                    "Malformed config file '{}':\n\t{}",
+                    fragment_filepath.display(),
+                    e,
+                ),
+            };
+
+            eprintln!("{}", error_msg);
+            exit(exitcode::DATAERR)
+        }
+    }
+}
+
+/// Loads and merges (in order) the included config files, and their own
+/// included files. Relative paths are resolved from `base_path`, which is the
+/// including file's directory for nested inclusions. The including files' paths
+/// are tracked in `visited_paths` to detect circular inclusions.
+fn get_config_fragments(
+    extends: &[PathBuf],
+    base_path: &Path,
+    config_hash_context: &mut Context,
+    visited_paths: &mut Vec<PathBuf>,
+    included_paths: &mut Vec<PathBuf>,
+) -> ProjectConfigFragment {
+    let mut merged_fragment = ProjectConfigFragment::default();
+
+    for include_path in extends {
+        let fragment_filepath = resolve_include_path(include_path, base_path, included_paths);
+        let fragment_bytes = get_file_bytes(&fragment_filepath);
+        included_paths.push(fragment_filepath.clone());
+
+        let canonical_fragment_path = get_canonical_path(&fragment_filepath);
+        if visited_paths.contains(&canonical_fragment_path) {
+            eprintln!(
+                "Circular inclusion detected for the config file '{}'",
+                fragment_filepath.display()
+            );
+            exit(exitcode::DATAERR)
+        }
+
+        // We hash the path as written in the config, and not the resolved one,
+        // because the latter depends on the local environment.
+        config_hash_context.update(b"\0");
+        config_hash_context.update(include_path.to_string_lossy().as_bytes());
+        config_hash_context.update(b"\0");
+        config_hash_context.update(&fragment_bytes);
+
+        let mut fragment = get_config_fragment(&fragment_filepath, &fragment_bytes);
+        if let Some(nested_extends) = fragment.extends.take() {
+            visited_paths.push(canonical_fragment_path);
+            let fragment_dir_path = match fragment_filepath.parent() {
+                Some(p) => p.to_path_buf(),
+                None => PathBuf::from("/"),
+            };
+            let base_fragment = get_config_fragments(
+                &nested_extends,
+                &fragment_dir_path,
+                config_hash_context,
+                visited_paths,
+                included_paths,
+            );
+            visited_paths.pop();
+
+            fragment = merge_config_fragments(&base_fragment, &fragment);
+        }
+
+        merged_fragment = merge_config_fragments(&merged_fragment, &fragment);
+    }
+
+    merged_fragment
+}
+
+fn get_project_path_from_config_path(config_filepath: &Path) -> &Path {
+    match config_filepath.parent().and_then(|p| p.parent()) {
+        Some(p) => p,
+        None => {
+            eprintln!(
+                "Unable to infer the project directory from the config file path '{}'",
+                config_filepath.display()
+            );
+            exit(exitcode::SOFTWARE)
+        }
+    }
+}
+
+fn get_canonical_path(filepath: &PathBuf) -> PathBuf {
+    match canonicalize(filepath) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!(
+                "Unable to resolve the path {}\n\n{}\n",
+                filepath.display(),
+                e
+            );
+            exit(exitcode::IOERR)
+        }
+    }
+}
+
+/// Malformed files (e.g. partially written ones) are reported to the caller, so
+/// they can be regenerated.
+pub(crate) fn read_config_lock(
+    config_lock_filepath: &PathBuf,
+) -> Result<(ProjectConfigLock, Digest), String> {
+    let config_lock_bytes = get_file_bytes(config_lock_filepath);
+
+    match serde_yaml::from_slice::<ProjectConfigLock>(&config_lock_bytes) {
+        Ok(_config_lock) => Ok((_config_lock, digest(&SHA256, &config_lock_bytes))),
+        Err(e) => Err(match e.location() {
+            Some(l) => format!(
+                "Malformed lock file '{}', line {}, column {}:\n\t{}",
+                config_lock_filepath.display(),
+                l.line(),
+                l.column(),
+                e,
+            ),
+            None => format!(
+                "Malformed lock file '{}':\n\t{}",
+                config_lock_filepath.display(),
+                e,
+            ),
+        }),
+    }
 }
 
 fn get_file_bytes(filepath: &PathBuf) -> Vec<u8> {
@@ -456,141 +1366,702 @@ fn get_file_bytes(filepath: &PathBuf) -> Vec<u8> {
 }
 
 fn merge_bindings(
-    base_bindings: &Option<BTreeMap<PathBuf, PathBuf>>,
-    new_bindings: &Option<BTreeMap<PathBuf, PathBuf>>,
-) -> Option<BTreeMap<PathBuf, PathBuf>> {
-    match base_bindings {
-        Some(_base_bindings) => match new_bindings {
-            Some(_new_bindings) => {
-                let mut merged_bindings = _base_bindings.clone();
-                for (container_path, host_path) in _new_bindings {
-                    merged_bindings.insert(container_path.clone(), host_path.clone());
+    base_bindings: &Option<BTreeMap<PathBuf, BindingConfig>>,
+    new_bindings: &Option<BTreeMap<PathBuf, BindingConfig>>,
+    unset_bindings: &Option<BTreeSet<PathBuf>>,
+) -> Option<BTreeMap<PathBuf, BindingConfig>> {
+    merge_maps(base_bindings, new_bindings, unset_bindings)
+}
+
+fn merge_config_fragments(
+    base_fragment: &ProjectConfigFragment,
+    new_fragment: &ProjectConfigFragment,
+) -> ProjectConfigFragment {
+    ProjectConfigFragment {
+        extends: None,
+        run_config: merge_run_config_layers(&base_fragment.run_config, &new_fragment.run_config),
+        images: merge_images(&base_fragment.images, &new_fragment.images),
+        profiles: merge_profiles(&base_fragment.profiles, &new_fragment.profiles),
+    }
+}
+
+/// Applies the personal config overrides on top of the project config.
+pub(crate) fn merge_local_config(
+    config: &ProjectConfig,
+    local_config: &ProjectConfigFragment,
+) -> ProjectConfig {
+    let mut merged_config = config.clone();
+    merged_config.images = merge_overriding_run_config(
+        &merge_images(config.get_images(), &local_config.images),
+        &local_config.run_config,
+    );
+    merged_config.profiles = merge_profiles(&config.profiles, &local_config.profiles);
+    merged_config
+}
+
+/// Applies the selected profile on top of the (already merged) project config.
+pub(crate) fn merge_profile(config: &ProjectConfig, profile_name: &str) -> ProjectConfig {
+    let profile = match config
+        .profiles
+        .as_ref()
+        .and_then(|profiles| profiles.get(profile_name))
+    {
+        Some(_profile) => _profile,
+        None => {
+            eprintln!(
+                "The profile '{}' is not defined in the project config",
+                profile_name
+            );
+            exit(exitcode::USAGE)
+        }
+    };
+
+    let mut merged_config = config.clone();
+    merged_config.images = merge_overriding_run_config(
+        &merge_images(config.get_images(), &profile.images),
+        &profile.run_config,
+    );
+    merged_config
+}
+
+/// Merges a `runConfig` meant to override the whole configuration (as the
+/// top-level one of profiles and local configs) on top of every binary level
+/// one, so it takes precedence over the project, image and binary settings.
+fn merge_overriding_run_config(
+    images: &Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>,
+    run_config: &Option<OCIContainerRunConfig>,
+) -> Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>> {
+    let mut merged_images = images.clone()?;
+    if run_config.is_none() {
+        return Some(merged_images);
+    }
+
+    for image_tags in merged_images.values_mut() {
+        for image_config in image_tags.values_mut() {
+            if let Some(binaries) = image_config.binaries.as_mut() {
+                for binary_config in binaries.values_mut() {
+                    binary_config.run_config =
+                        merge_run_config_layers(&binary_config.run_config, run_config);
+                }
+            }
+        }
+    }
+    Some(merged_images)
+}
+
+fn merge_profiles(
+    base_profiles: &Option<BTreeMap<String, ProfileConfig>>,
+    new_profiles: &Option<BTreeMap<String, ProfileConfig>>,
+) -> Option<BTreeMap<String, ProfileConfig>> {
+    match base_profiles {
+        Some(_base_profiles) => match new_profiles {
+            Some(_new_profiles) => {
+                let mut merged_profiles = _base_profiles.clone();
+                for (profile_name, profile) in _new_profiles {
+                    let merged_profile = match _base_profiles.get(profile_name) {
+                        Some(base_profile) => ProfileConfig {
+                            run_config: merge_run_config_layers(
+                                &base_profile.run_config,
+                                &profile.run_config,
+                            ),
+                            images: merge_images(&base_profile.images, &profile.images),
+                        },
+                        None => profile.clone(),
+                    };
+                    merged_profiles.insert(profile_name.clone(), merged_profile);
                 }
-                Some(merged_bindings)
+                Some(merged_profiles)
             }
-            None => base_bindings.clone(),
+            None => base_profiles.clone(),
         },
-        None => new_bindings.clone(),
+        None => new_profiles.clone(),
     }
 }
 
 fn merge_envs(
     base_env: &Option<BTreeMap<String, String>>,
     new_env: &Option<BTreeMap<String, String>>,
+    unset_env: &Option<BTreeSet<String>>,
 ) -> Option<BTreeMap<String, String>> {
+    merge_maps(base_env, new_env, unset_env)
+}
+
+/// Entries are identified by their container variable name (or pattern), so the
+/// new entries replace the base ones with the same name.
+fn merge_envs_from_host(
+    base_env: &Option<BTreeSet<EnvFromHostConfig>>,
+    new_env: &Option<BTreeSet<EnvFromHostConfig>>,
+    unset_env: &Option<BTreeSet<String>>,
+) -> Option<BTreeSet<EnvFromHostConfig>> {
     match base_env {
-        Some(_base_env) => match new_env {
-            Some(_new_env) => {
-                let mut merged_env = _base_env.clone();
-                for (var_name, var_value) in _new_env {
-                    merged_env.insert(var_name.clone(), var_value.clone());
+        Some(_base_env) => {
+            let mut merged_env: BTreeSet<EnvFromHostConfig> = _base_env
+                .iter()
+                .filter(|entry| match unset_env {
+                    Some(_unset_env) => !_unset_env.contains(entry.get_name()),
+                    None => true,
+                })
+                .filter(|entry| match new_env {
+                    Some(_new_env) => !_new_env
+                        .iter()
+                        .any(|new_entry| new_entry.get_name() == entry.get_name()),
+                    None => true,
+                })
+                .cloned()
+                .collect();
+            if let Some(_new_env) = new_env {
+                merged_env.extend(_new_env.iter().cloned());
+            }
+            Some(merged_env)
+        }
+        None => new_env.clone(),
+    }
+}
+
+fn merge_image_binaries(
+    base_binaries: &Option<BTreeMap<String, ImageBinaryConfig>>,
+    new_binaries: &Option<BTreeMap<String, ImageBinaryConfig>>,
+) -> Option<BTreeMap<String, ImageBinaryConfig>> {
+    match base_binaries {
+        Some(_base_binaries) => match new_binaries {
+            Some(_new_binaries) => {
+                let mut merged_binaries = _base_binaries.clone();
+                for (binary_name, binary_config) in _new_binaries {
+                    let merged_binary_config = match _base_binaries.get(binary_name) {
+                        Some(base_binary_config) => ImageBinaryConfig {
+                            path: binary_config
+                                .get_path()
+                                .clone()
+                                .or_else(|| base_binary_config.get_path().clone()),
+                            run_config: merge_run_config_layers(
+                                base_binary_config.get_run_config(),
+                                binary_config.get_run_config(),
+                            ),
+                        },
+                        None => binary_config.clone(),
+                    };
+                    merged_binaries.insert(binary_name.clone(), merged_binary_config);
                 }
-                Some(merged_env)
+                Some(merged_binaries)
             }
-            None => base_env.clone(),
+            None => base_binaries.clone(),
         },
-        None => new_env.clone(),
+        None => new_binaries.clone(),
     }
 }
 
-fn merge_envs_from_host(
-    base_env: &Option<BTreeSet<String>>,
-    new_env: &Option<BTreeSet<String>>,
-) -> Option<BTreeSet<String>> {
-    match base_env {
-        Some(_base_env) => match new_env {
-            Some(_new_env) => Some(_base_env.union(_new_env).cloned().collect()),
-            None => base_env.clone(),
+fn merge_images(
+    base_images: &Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>,
+    new_images: &Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>>,
+) -> Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>> {
+    match base_images {
+        Some(_base_images) => match new_images {
+            Some(_new_images) => {
+                let mut merged_images = _base_images.clone();
+                for (image_name, image_tags) in _new_images {
+                    let merged_tags = merged_images
+                        .entry(image_name.clone())
+                        .or_insert_with(BTreeMap::new);
+
+                    for (image_tag, image_config) in image_tags {
+                        let merged_image_config = match merged_tags.get(image_tag) {
+                            Some(base_image_config) => OCIImageConfig {
+                                binaries: merge_image_binaries(
+                                    base_image_config.get_binaries(),
+                                    image_config.get_binaries(),
+                                ),
+                                run_config: merge_run_config_layers(
+                                    base_image_config.get_run_config(),
+                                    image_config.get_run_config(),
+                                ),
+                            },
+                            None => image_config.clone(),
+                        };
+                        merged_tags.insert(image_tag.clone(), merged_image_config);
+                    }
+                }
+                Some(merged_images)
+            }
+            None => base_images.clone(),
         },
-        None => new_env.clone(),
+        None => new_images.clone(),
     }
 }
 
-pub(crate) fn merge_run_configs(
+/// Merges two lists keeping their order, the items of `new_list` are moved to
+/// the end, and the ones listed in `unset_items` are removed from `base_list`.
+fn merge_lists<T: Clone + Ord>(
+    base_list: &Option<Vec<T>>,
+    new_list: &Option<Vec<T>>,
+    unset_items: &Option<BTreeSet<T>>,
+) -> Option<Vec<T>> {
+    match base_list {
+        Some(_base_list) => {
+            let mut merged_list: Vec<T> = _base_list
+                .iter()
+                .filter(|item| match unset_items {
+                    Some(_unset_items) => !_unset_items.contains(item),
+                    None => true,
+                })
+                .filter(|item| match new_list {
+                    Some(_new_list) => !_new_list.contains(item),
+                    None => true,
+                })
+                .cloned()
+                .collect();
+            if let Some(_new_list) = new_list {
+                merged_list.extend(_new_list.iter().cloned());
+            }
+            Some(merged_list)
+        }
+        None => new_list.clone(),
+    }
+}
+
+/// Merges two maps, the entries of `new_map` override the ones of `base_map`,
+/// and the keys listed in `unset_keys` are removed from `base_map` beforehand.
+fn merge_maps<K: Clone + Ord, V: Clone>(
+    base_map: &Option<BTreeMap<K, V>>,
+    new_map: &Option<BTreeMap<K, V>>,
+    unset_keys: &Option<BTreeSet<K>>,
+) -> Option<BTreeMap<K, V>> {
+    match base_map {
+        Some(_base_map) => {
+            let mut merged_map = _base_map.clone();
+            if let Some(_unset_keys) = unset_keys {
+                for key in _unset_keys {
+                    merged_map.remove(key);
+                }
+            }
+            if let Some(_new_map) = new_map {
+                for (key, value) in _new_map {
+                    merged_map.insert(key.clone(), value.clone());
+                }
+            }
+            Some(merged_map)
+        }
+        None => new_map.clone(),
+    }
+}
+
+fn merge_run_config_layers(
     base_config: &Option<OCIContainerRunConfig>,
     new_config: &Option<OCIContainerRunConfig>,
-    project_internal_id: &str,
-    image_ref: &str,
-    binary_name: &str,
-) -> Option<OCIContainerRunConfigLock> {
+) -> Option<OCIContainerRunConfig> {
     match base_config {
         Some(_base_config) => match new_config {
-            Some(_new_config) => Some(OCIContainerRunConfigLock {
-                bindings: merge_bindings(_base_config.get_bindings(), _new_config.get_bindings()),
-                volumes: merge_volumes(
-                    _base_config.get_volumes(),
-                    _new_config.get_volumes(),
-                    project_internal_id,
-                    image_ref,
-                    binary_name,
-                ),
-                env: merge_envs(_base_config.get_env(), _new_config.get_env()),
-                env_from_host: merge_envs_from_host(
-                    _base_config.get_env_from_host(),
-                    _new_config.get_env_from_host(),
+            Some(_new_config) => {
+                let no_unset = OCIContainerRunConfigUnset::default();
+                let unset = _new_config.get_unset().as_ref().unwrap_or(&no_unset);
+
+                Some(OCIContainerRunConfig {
+                    env: merge_envs(
+                        _base_config.get_env(),
+                        _new_config.get_env(),
+                        unset.get_env(),
+                    ),
+                    env_file: merge_lists(
+                        _base_config.get_env_file(),
+                        _new_config.get_env_file(),
+                        unset.get_env_file(),
+                    ),
+                    env_from_host: merge_envs_from_host(
+                        _base_config.get_env_from_host(),
+                        _new_config.get_env_from_host(),
+                        unset.get_env_from_host(),
+                    ),
+                    path_prepend: merge_lists(
+                        _base_config.get_path_prepend(),
+                        _new_config.get_path_prepend(),
+                        unset.get_path_prepend(),
+                    ),
+                    path_append: merge_lists(
+                        _base_config.get_path_append(),
+                        _new_config.get_path_append(),
+                        unset.get_path_append(),
+                    ),
+                    volumes: merge_volumes(
+                        _base_config.get_volumes(),
+                        _new_config.get_volumes(),
+                        unset.get_volumes(),
+                    ),
+                    tmpfs: merge_maps(
+                        _base_config.get_tmpfs(),
+                        _new_config.get_tmpfs(),
+                        unset.get_tmpfs(),
+                    ),
+                    bindings: merge_bindings(
+                        _base_config.get_bindings(),
+                        _new_config.get_bindings(),
+                        unset.get_bindings(),
+                    ),
+                    project_mount: merge_values(
+                        _base_config.get_project_mount(),
+                        _new_config.get_project_mount(),
+                    ),
+                    supervised: merge_values(
+                        _base_config.get_supervised(),
+                        _new_config.get_supervised(),
+                    ),
+                    persistent: merge_values(
+                        _base_config.get_persistent(),
+                        _new_config.get_persistent(),
+                    ),
+                    interactive: merge_values(
+                        _base_config.get_interactive(),
+                        _new_config.get_interactive(),
+                    ),
+                    tty: merge_values(_base_config.get_tty(), _new_config.get_tty()),
+                    workdir: merge_values(_base_config.get_workdir(), _new_config.get_workdir()),
+                    translate_arg_paths: merge_values(
+                        _base_config.get_translate_arg_paths(),
+                        _new_config.get_translate_arg_paths(),
+                    ),
+                    strict_interpolation: merge_values(
+                        _base_config.get_strict_interpolation(),
+                        _new_config.get_strict_interpolation(),
+                    ),
+                    selinux_relabel: merge_values(
+                        _base_config.get_selinux_relabel(),
+                        _new_config.get_selinux_relabel(),
+                    ),
+                    resources: merge_resources(
+                        _base_config.get_resources(),
+                        _new_config.get_resources(),
+                        unset.get_ulimits(),
+                    ),
+                    network: merge_values(_base_config.get_network(), _new_config.get_network()),
+                    security: merge_security(
+                        _base_config.get_security(),
+                        _new_config.get_security(),
+                    ),
+                    user: merge_values(_base_config.get_user(), _new_config.get_user()),
+                    groups: merge_groups(
+                        _base_config.get_groups(),
+                        _new_config.get_groups(),
+                        unset.get_groups(),
+                    ),
+                    ports: merge_lists(
+                        _base_config.get_ports(),
+                        _new_config.get_ports(),
+                        unset.get_ports(),
+                    ),
+                    unset: merge_unsets(
+                        _base_config.get_unset(),
+                        &get_remaining_unset(_base_config, unset),
+                    ),
+                })
+            }
+            None => base_config.clone(),
+        },
+        None => new_config.clone(),
+    }
+}
+
+/// Scalar settings are simply overridden by the higher precedence layers
+fn merge_values<T: Clone>(base_value: &Option<T>, new_value: &Option<T>) -> Option<T> {
+    match new_value {
+        Some(_) => new_value.clone(),
+        None => base_value.clone(),
+    }
+}
+
+fn merge_sets<T: Clone + Ord>(
+    base_set: &Option<BTreeSet<T>>,
+    new_set: &Option<BTreeSet<T>>,
+) -> Option<BTreeSet<T>> {
+    match base_set {
+        Some(_base_set) => match new_set {
+            Some(_new_set) => Some(_base_set.union(_new_set).cloned().collect()),
+            None => base_set.clone(),
+        },
+        None => new_set.clone(),
+    }
+}
+
+/// The `unset` entries that didn't match any setting of `base_config` are kept,
+/// as they still apply to the run configs defined at lower precedence levels
+/// (e.g. the project level ones, for an image level `unset` that is merged with
+/// an extended file). This way, each entry removes a setting only once.
+fn get_remaining_unset(
+    base_config: &OCIContainerRunConfig,
+    unset: &OCIContainerRunConfigUnset,
+) -> Option<OCIContainerRunConfigUnset> {
+    fn remaining<T: Clone + Ord>(
+        unset_items: &Option<BTreeSet<T>>,
+        is_defined: impl Fn(&T) -> bool,
+    ) -> Option<BTreeSet<T>> {
+        let remaining_items: BTreeSet<T> = unset_items
+            .as_ref()?
+            .iter()
+            .filter(|item| !is_defined(item))
+            .cloned()
+            .collect();
+        match remaining_items.is_empty() {
+            true => None,
+            false => Some(remaining_items),
+        }
+    }
+
+    let remaining_unset = OCIContainerRunConfigUnset {
+        env: remaining(
+            &unset.env,
+            |name| matches!(&base_config.env, Some(env) if env.contains_key(name)),
+        ),
+        env_file: remaining(
+            &unset.env_file,
+            |path| matches!(&base_config.env_file, Some(env_file) if env_file.contains(path)),
+        ),
+        env_from_host: remaining(
+            &unset.env_from_host,
+            |name| matches!(&base_config.env_from_host, Some(env) if env.iter().any(|entry| entry.get_name() == name)),
+        ),
+        path_prepend: remaining(
+            &unset.path_prepend,
+            |path| matches!(&base_config.path_prepend, Some(paths) if paths.contains(path)),
+        ),
+        path_append: remaining(
+            &unset.path_append,
+            |path| matches!(&base_config.path_append, Some(paths) if paths.contains(path)),
+        ),
+        volumes: remaining(
+            &unset.volumes,
+            |path| matches!(&base_config.volumes, Some(volumes) if volumes.contains_key(path)),
+        ),
+        tmpfs: remaining(
+            &unset.tmpfs,
+            |path| matches!(&base_config.tmpfs, Some(tmpfs) if tmpfs.contains_key(path)),
+        ),
+        ports: remaining(
+            &unset.ports,
+            |port| matches!(&base_config.ports, Some(ports) if ports.contains(port)),
+        ),
+        groups: remaining(
+            &unset.groups,
+            |group| matches!(&base_config.groups, Some(groups) if groups.contains(group)),
+        ),
+        bindings: remaining(
+            &unset.bindings,
+            |path| matches!(&base_config.bindings, Some(bindings) if bindings.contains_key(path)),
+        ),
+        ulimits: remaining(&unset.ulimits, |name| {
+            matches!(
+                base_config.resources.as_ref().and_then(|r| r.ulimits.as_ref()),
+                Some(ulimits) if ulimits.contains_key(name)
+            )
+        }),
+    };
+
+    match remaining_unset == OCIContainerRunConfigUnset::default() {
+        true => None,
+        false => Some(remaining_unset),
+    }
+}
+
+fn merge_unsets(
+    base_unset: &Option<OCIContainerRunConfigUnset>,
+    new_unset: &Option<OCIContainerRunConfigUnset>,
+) -> Option<OCIContainerRunConfigUnset> {
+    match base_unset {
+        Some(_base_unset) => match new_unset {
+            Some(_new_unset) => Some(OCIContainerRunConfigUnset {
+                env: merge_sets(_base_unset.get_env(), _new_unset.get_env()),
+                env_file: merge_sets(_base_unset.get_env_file(), _new_unset.get_env_file()),
+                env_from_host: merge_sets(
+                    _base_unset.get_env_from_host(),
+                    _new_unset.get_env_from_host(),
                 ),
-            }),
-            None => Some(OCIContainerRunConfigLock {
-                bindings: _base_config.bindings.clone(),
-                volumes: generate_volume_config_lock(
-                    &_base_config.volumes,
-                    project_internal_id,
-                    image_ref,
-                    binary_name,
+                path_prepend: merge_sets(
+                    _base_unset.get_path_prepend(),
+                    _new_unset.get_path_prepend(),
                 ),
-                env: _base_config.env.clone(),
-                env_from_host: _base_config.env_from_host.clone(),
-            }),
-        },
-        None => match new_config {
-            Some(_new_config) => Some(OCIContainerRunConfigLock {
-                bindings: _new_config.bindings.clone(),
-                volumes: generate_volume_config_lock(
-                    &_new_config.volumes,
-                    project_internal_id,
-                    image_ref,
-                    binary_name,
+                path_append: merge_sets(
+                    _base_unset.get_path_append(),
+                    _new_unset.get_path_append(),
                 ),
-                env: _new_config.env.clone(),
-                env_from_host: _new_config.env_from_host.clone(),
+                volumes: merge_sets(_base_unset.get_volumes(), _new_unset.get_volumes()),
+                tmpfs: merge_sets(_base_unset.get_tmpfs(), _new_unset.get_tmpfs()),
+                ports: merge_sets(_base_unset.get_ports(), _new_unset.get_ports()),
+                groups: merge_sets(_base_unset.get_groups(), _new_unset.get_groups()),
+                bindings: merge_sets(_base_unset.get_bindings(), _new_unset.get_bindings()),
+                ulimits: merge_sets(_base_unset.get_ulimits(), _new_unset.get_ulimits()),
             }),
-            None => Option::<OCIContainerRunConfigLock>::None,
+            None => base_unset.clone(),
         },
+        None => new_unset.clone(),
     }
 }
 
-fn merge_volumes(
-    base_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
-    new_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
+/// Merges the project, image and binary level run configs (in that order of
+/// precedence), removing the inherited settings listed in the `unset` blocks,
+/// and generates the run config that will be stored in the lock file.
+pub(crate) fn merge_run_configs(
+    project_config: &Option<OCIContainerRunConfig>,
+    image_config: &Option<OCIContainerRunConfig>,
+    binary_config: &Option<OCIContainerRunConfig>,
     project_internal_id: &str,
     image_ref: &str,
     binary_name: &str,
-) -> Option<Vec<VolumeConfigLock>> {
-    match base_volumes {
-        Some(_base_volumes) => match new_volumes {
-            Some(_new_volumes) => {
-                let mut merged_volumes = _base_volumes.clone();
-                for (var_name, var_value) in _new_volumes {
-                    merged_volumes.insert(var_name.clone(), var_value.clone());
-                }
-                generate_volume_config_lock(
-                    &Some(merged_volumes),
-                    project_internal_id,
-                    image_ref,
-                    binary_name,
-                )
-            }
-            None => generate_volume_config_lock(
-                base_volumes,
+) -> Option<OCIContainerRunConfigLock> {
+    match merge_run_config_layers(
+        &merge_run_config_layers(project_config, image_config),
+        binary_config,
+    ) {
+        Some(merged_config) => Some(OCIContainerRunConfigLock {
+            ports: validate_ports(merged_config.ports, &merged_config.network, binary_name),
+            network: merged_config.network,
+            user: validate_user(merged_config.user, binary_name),
+            groups: merged_config.groups,
+            security: merged_config
+                .security
+                .map(|security| expand_security_preset(security, binary_name)),
+            resources: merged_config
+                .resources
+                .map(|resources| validate_resources(resources, binary_name)),
+            bindings: merged_config.bindings,
+            tmpfs: merged_config.tmpfs,
+            project_mount: merged_config.project_mount,
+            supervised: merged_config.supervised,
+            persistent: merged_config.persistent,
+            interactive: merged_config.interactive,
+            tty: merged_config.tty,
+            workdir: validate_workdir(merged_config.workdir, binary_name),
+            translate_arg_paths: merged_config.translate_arg_paths,
+            strict_interpolation: merged_config.strict_interpolation,
+            selinux_relabel: merged_config.selinux_relabel,
+            volumes: generate_volume_config_lock(
+                &merged_config.volumes,
                 project_internal_id,
                 image_ref,
                 binary_name,
             ),
+            env: merged_config.env,
+            env_file: merged_config.env_file,
+            env_from_host: merged_config.env_from_host,
+            path_prepend: merged_config.path_prepend,
+            path_append: merged_config.path_append,
+        }),
+        None => Option::<OCIContainerRunConfigLock>::None,
+    }
+}
+
+fn merge_resources(
+    base_resources: &Option<ResourcesConfig>,
+    new_resources: &Option<ResourcesConfig>,
+    unset_ulimits: &Option<BTreeSet<String>>,
+) -> Option<ResourcesConfig> {
+    let no_resources = ResourcesConfig::default();
+
+    match base_resources {
+        Some(_base_resources) => {
+            let _new_resources = new_resources.as_ref().unwrap_or(&no_resources);
+            Some(ResourcesConfig {
+                memory: merge_values(_base_resources.get_memory(), _new_resources.get_memory()),
+                memory_swap: merge_values(
+                    _base_resources.get_memory_swap(),
+                    _new_resources.get_memory_swap(),
+                ),
+                cpus: merge_values(_base_resources.get_cpus(), _new_resources.get_cpus()),
+                pids_limit: merge_values(
+                    _base_resources.get_pids_limit(),
+                    _new_resources.get_pids_limit(),
+                ),
+                ulimits: merge_maps(
+                    _base_resources.get_ulimits(),
+                    _new_resources.get_ulimits(),
+                    unset_ulimits,
+                ),
+            })
+        }
+        None => new_resources.clone(),
+    }
+}
+
+fn merge_security(
+    base_security: &Option<SecurityConfig>,
+    new_security: &Option<SecurityConfig>,
+) -> Option<SecurityConfig> {
+    match base_security {
+        Some(_base_security) => match new_security {
+            Some(_new_security) => Some(SecurityConfig {
+                preset: merge_values(_base_security.get_preset(), _new_security.get_preset()),
+                cap_drop: merge_sets(_base_security.get_cap_drop(), _new_security.get_cap_drop()),
+                cap_add: merge_sets(_base_security.get_cap_add(), _new_security.get_cap_add()),
+                no_new_privileges: merge_values(
+                    _base_security.get_no_new_privileges(),
+                    _new_security.get_no_new_privileges(),
+                ),
+                read_only_root_filesystem: merge_values(
+                    _base_security.get_read_only_root_filesystem(),
+                    _new_security.get_read_only_root_filesystem(),
+                ),
+                seccomp_profile: merge_values(
+                    _base_security.get_seccomp_profile(),
+                    _new_security.get_seccomp_profile(),
+                ),
+            }),
+            None => base_security.clone(),
         },
-        None => {
-            generate_volume_config_lock(new_volumes, project_internal_id, image_ref, binary_name)
+        None => new_security.clone(),
+    }
+}
+
+/// As with the other settings, the `unset_groups` are only removed from
+/// `base_groups`, so the same layer can't cancel its own groups.
+fn merge_groups(
+    base_groups: &Option<BTreeSet<String>>,
+    new_groups: &Option<BTreeSet<String>>,
+    unset_groups: &Option<BTreeSet<String>>,
+) -> Option<BTreeSet<String>> {
+    let inherited_groups = match (base_groups, unset_groups) {
+        (Some(_base_groups), Some(_unset_groups)) => Some(
+            _base_groups
+                .difference(_unset_groups)
+                .cloned()
+                .collect::<BTreeSet<String>>(),
+        ),
+        _ => base_groups.clone(),
+    };
+    merge_sets(&inherited_groups, new_groups)
+}
+
+fn merge_volumes(
+    base_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
+    new_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
+    unset_volumes: &Option<BTreeSet<PathBuf>>,
+) -> Option<BTreeMap<PathBuf, VolumeConfig>> {
+    merge_maps(base_volumes, new_volumes, unset_volumes)
+}
+
+/// The candidate paths that don't exist are appended to `missing_paths`
+fn resolve_include_path(
+    include_path: &PathBuf,
+    base_path: &Path,
+    missing_paths: &mut Vec<PathBuf>,
+) -> PathBuf {
+    if include_path.is_absolute() {
+        return include_path.clone();
+    }
+
+    let base_relative_path = base_path.join(include_path);
+    if base_relative_path.is_file() {
+        return base_relative_path;
+    }
+    missing_paths.push(base_relative_path);
+
+    if let Some(user_config_path) = get_user_config_path() {
+        let user_relative_path = user_config_path.join(include_path);
+        if user_relative_path.is_file() {
+            return user_relative_path;
         }
     }
+
+    eprintln!(
+        "The included config file '{}' was not found in '{}' nor in the user config directory",
+        include_path.display(),
+        base_path.display()
+    );
+    exit(exitcode::NOINPUT)
 }
 
 pub(crate) fn save_config(config_filepath: &PathBuf, config: &ProjectConfig) -> Vec<u8> {
@@ -619,22 +2090,692 @@ fn save_result_to_file(
 ) -> Vec<u8> {
     match result {
         Ok(serialized_bytes) => {
-            if let Err(e) = write(filepath, &serialized_bytes) {
-                eprintln!(
-                    "Unknown error while persisting {}:\n\n{}\n",
-                    result_type,
-                    e.to_string()
-                );
+            if let Err(e) = write_atomically(filepath, &serialized_bytes) {
+                eprintln!("Unknown error while persisting {}:\n\n{}\n", result_type, e);
+                exit(exitcode::CANTCREAT)
             }
             serialized_bytes
         }
         Err(e) => {
             eprintln!(
                 "Unknown error while serializing {}:\n\n{}\n",
-                result_type,
-                e.to_string()
+                result_type, e
             );
             exit(exitcode::SOFTWARE)
         }
     }
 }
+
+/// Writes into a temporary file placed in the same directory, and then renames
+/// it, so readers never find a partially written file.
+pub(crate) fn write_atomically(filepath: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = match filepath.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return Err(io::Error::new(ErrorKind::InvalidInput, "Not a file path")),
+    };
+    remove_stale_temporary_files(filepath, &file_name);
+    let tmp_filepath = filepath.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+
+    let mut tmp_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_filepath)?;
+    let result = tmp_file
+        .write_all(contents)
+        .and_then(|_| tmp_file.sync_all())
+        .and_then(|_| rename(&tmp_filepath, filepath));
+
+    if result.is_err() {
+        let _ = remove_file(&tmp_filepath);
+    }
+    result
+}
+
+/// Removes the temporary files left by writers that were killed before
+/// renaming them. The ones that belong to running processes are kept.
+fn remove_stale_temporary_files(filepath: &Path, file_name: &str) {
+    let dir_path = match filepath.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    let entries = match read_dir(dir_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let prefix = format!(".{}.", file_name);
+    for entry in entries.flatten() {
+        let entry_name = entry.file_name().to_string_lossy().to_string();
+        let writer_pid = entry_name
+            .strip_prefix(&prefix)
+            .and_then(|suffix| suffix.strip_suffix(".tmp"))
+            .and_then(|pid| pid.parse::<i32>().ok());
+
+        if let Some(writer_pid) = writer_pid {
+            if writer_pid == process::id() as i32 || !is_process_alive(writer_pid) {
+                let _ = remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Parses memory sizes in docker's format (e.g. 512m, 2g), returning bytes
+fn parse_memory_size(size: &str) -> Option<u64> {
+    // Only ASCII suffixes are accepted, other characters can change their
+    // length when converted to lowercase
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, suffix)) => match suffix.to_ascii_lowercase() {
+            'b' => (&size[..i], 1),
+            'k' => (&size[..i], 1 << 10),
+            'm' => (&size[..i], 1 << 20),
+            'g' => (&size[..i], 1 << 30),
+            _ => (size, 1),
+        },
+        None => (size, 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) => number.checked_mul(multiplier),
+        Err(_) => None,
+    }
+}
+
+/// Checks the resource limits at lock time, so the containers don't fail to
+/// start because of them.
+fn validate_resources(resources: ResourcesConfig, binary_name: &str) -> ResourcesConfig {
+    let fail = |setting_name: &str, message: String| -> ! {
+        eprintln!(
+            "Invalid resources.{} setting for the binary '{}': {}",
+            setting_name, binary_name, message
+        );
+        exit(exitcode::CONFIG)
+    };
+
+    let memory = match resources.get_memory() {
+        Some(memory) => match parse_memory_size(memory) {
+            Some(memory_bytes) if memory_bytes >= 6 << 20 => Some(memory_bytes),
+            Some(_) => fail("memory", "the minimum allowed value is 6m".to_string()),
+            None => fail("memory", format!("'{}' is not a valid size", memory)),
+        },
+        None => None,
+    };
+
+    if let Some(memory_swap) = resources.get_memory_swap() {
+        let memory = match memory {
+            Some(memory) => memory,
+            None => fail(
+                "memorySwap",
+                "it requires setting resources.memory".to_string(),
+            ),
+        };
+        if memory_swap != "-1" {
+            match parse_memory_size(memory_swap) {
+                Some(memory_swap_bytes) if memory_swap_bytes >= memory => {}
+                Some(_) => fail(
+                    "memorySwap",
+                    "it must be greater than or equal to resources.memory".to_string(),
+                ),
+                None => fail(
+                    "memorySwap",
+                    format!("'{}' is not a valid size", memory_swap),
+                ),
+            }
+        }
+    }
+
+    if let Some(cpus) = resources.get_cpus() {
+        if !cpus.is_finite() || *cpus <= 0.0 {
+            fail("cpus", "it must be a positive number".to_string())
+        }
+    }
+
+    if let Some(pids_limit) = resources.get_pids_limit() {
+        if *pids_limit == 0 || *pids_limit < -1 {
+            fail(
+                "pidsLimit",
+                "it must be a positive number, or -1 for unlimited".to_string(),
+            )
+        }
+    }
+
+    if let Some(ulimits) = resources.get_ulimits() {
+        for (ulimit_name, ulimit_config) in ulimits {
+            let setting_name = format!("ulimits.{}", ulimit_name);
+            if !ULIMIT_NAMES.contains(&ulimit_name.as_str()) {
+                fail(&setting_name, "unknown ulimit".to_string())
+            }
+            if ulimit_config.get_soft() > ulimit_config.get_hard() {
+                fail(
+                    &setting_name,
+                    "the soft limit can't be greater than the hard limit".to_string(),
+                )
+            }
+        }
+    }
+
+    resources
+}
+
+/// Parses port mappings with the `[host_ip:](host_port|auto):container_port[/protocol]` format
+pub(crate) fn parse_port_mapping(port_mapping: &str) -> Option<PortMapping> {
+    let (ports, protocol) = match port_mapping.rfind('/') {
+        Some(idx) => (&port_mapping[..idx], &port_mapping[idx + 1..]),
+        None => (port_mapping, "tcp"),
+    };
+    if protocol != "tcp" && protocol != "udp" {
+        return None;
+    }
+
+    let (host_ip, host_port, container_port) = match ports.rsplitn(3, ':').collect::<Vec<_>>()[..] {
+        [container_port, host_port] => (None, host_port, container_port),
+        [container_port, host_port, host_ip] if !host_ip.is_empty() => {
+            (Some(host_ip.to_string()), host_port, container_port)
+        }
+        _ => return None,
+    };
+
+    let container_port = match container_port.parse::<u16>() {
+        Ok(port) if port > 0 => port,
+        _ => return None,
+    };
+    let host_port = match host_port {
+        "auto" => None,
+        _ => match host_port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(port),
+            _ => return None,
+        },
+    };
+
+    Some(PortMapping {
+        host_ip,
+        host_port,
+        container_port,
+        protocol: protocol.to_string(),
+    })
+}
+
+fn validate_ports(
+    ports: Option<Vec<String>>,
+    network: &Option<String>,
+    binary_name: &str,
+) -> Option<Vec<String>> {
+    if let Some(_ports) = &ports {
+        if let Some(network) = network {
+            if !_ports.is_empty() && (network == "host" || network == "none") {
+                eprintln!(
+                    "Ports can't be published for the binary '{}', as it uses the '{}' network",
+                    binary_name, network
+                );
+                exit(exitcode::CONFIG)
+            }
+        }
+
+        for port_mapping in _ports {
+            if parse_port_mapping(port_mapping).is_none() {
+                eprintln!(
+                    "Invalid port mapping for the binary '{}': '{}', the expected format is [host_ip:](host_port|auto):container_port[/protocol]",
+                    binary_name, port_mapping
+                );
+                exit(exitcode::CONFIG)
+            }
+        }
+    }
+
+    ports
+}
+
+/// Replaces the security preset by the settings it implies, and validates the
+/// capability names.
+fn expand_security_preset(security: SecurityConfig, binary_name: &str) -> SecurityConfig {
+    for capability in security
+        .get_cap_drop()
+        .iter()
+        .chain(security.get_cap_add().iter())
+        .flatten()
+    {
+        if capability.is_empty()
+            || !capability
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            eprintln!(
+                "Invalid capability name for the binary '{}': '{}'",
+                binary_name, capability
+            );
+            exit(exitcode::CONFIG)
+        }
+    }
+
+    match security.get_preset() {
+        Some(SecurityPreset::Strict) => {
+            let mut cap_drop = security.get_cap_drop().clone().unwrap_or_default();
+            cap_drop.insert("ALL".to_string());
+
+            SecurityConfig {
+                preset: None,
+                cap_drop: Some(cap_drop),
+                cap_add: security.cap_add,
+                no_new_privileges: security.no_new_privileges.or(Some(true)),
+                read_only_root_filesystem: security.read_only_root_filesystem.or(Some(true)),
+                seccomp_profile: security.seccomp_profile,
+            }
+        }
+        _ => SecurityConfig {
+            preset: None,
+            ..security
+        },
+    }
+}
+
+/// Parses the `user` setting, a missing value means `host`
+pub(crate) fn parse_container_user(user: &Option<String>) -> Option<ContainerUser> {
+    match user.as_deref() {
+        None | Some("host") => Some(ContainerUser::Host),
+        Some("root") => Some(ContainerUser::Root),
+        Some("image") => Some(ContainerUser::Image),
+        Some(user_ids) => match user_ids.split(':').collect::<Vec<_>>()[..] {
+            [uid, gid] => match (uid.parse::<u32>(), gid.parse::<u32>()) {
+                (Ok(uid), Ok(gid)) => Some(ContainerUser::Id(uid, gid)),
+                _ => None,
+            },
+            _ => None,
+        },
+    }
+}
+
+fn validate_user(user: Option<String>, binary_name: &str) -> Option<String> {
+    if parse_container_user(&user).is_none() {
+        eprintln!(
+            "Invalid user for the binary '{}': '{}', allowed values are host, root, image or uid:gid",
+            binary_name,
+            user.unwrap_or_default()
+        );
+        exit(exitcode::CONFIG)
+    }
+
+    user
+}
+
+/// Parses the `workdir` setting, a missing value means `cwd`
+pub(crate) fn parse_workdir_policy(workdir: &Option<String>) -> Option<WorkdirPolicy> {
+    match workdir.as_deref() {
+        None | Some("cwd") => Some(WorkdirPolicy::Cwd),
+        Some("project-root") => Some(WorkdirPolicy::ProjectRoot),
+        Some(subdir) => {
+            let subdir_path = PathBuf::from(subdir);
+            let stays_inside_project = subdir_path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+            if subdir.is_empty() || !stays_inside_project {
+                None
+            } else {
+                // Removes the `.` components
+                Some(WorkdirPolicy::Subdir(
+                    subdir_path
+                        .components()
+                        .filter(|component| component != &Component::CurDir)
+                        .collect(),
+                ))
+            }
+        }
+    }
+}
+
+fn validate_workdir(workdir: Option<String>, binary_name: &str) -> Option<String> {
+    if parse_workdir_policy(&workdir).is_none() {
+        eprintln!(
+            "Invalid workdir for the binary '{}': '{}', allowed values are cwd, project-root or a relative path inside the project",
+            binary_name,
+            workdir.unwrap_or_default()
+        );
+        exit(exitcode::CONFIG)
+    }
+
+    workdir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_dir;
+    use std::fs::{create_dir, remove_dir_all, write};
+
+    fn parse_run_config(yaml: &str) -> Option<OCIContainerRunConfig> {
+        Some(serde_yaml::from_str::<OCIContainerRunConfig>(yaml).unwrap())
+    }
+
+    fn set_of(items: &[&str]) -> Option<BTreeSet<String>> {
+        Some(items.iter().map(|item| item.to_string()).collect())
+    }
+
+    #[test]
+    fn merged_maps_drop_unset_keys_before_adding_new_entries() {
+        let base: Option<BTreeMap<String, u8>> = Some(
+            vec![("A".to_string(), 1), ("B".to_string(), 2)]
+                .into_iter()
+                .collect(),
+        );
+        let new: Option<BTreeMap<String, u8>> = Some(
+            vec![("B".to_string(), 3), ("C".to_string(), 4)]
+                .into_iter()
+                .collect(),
+        );
+
+        let merged = merge_maps(&base, &new, &set_of(&["A", "B"])).unwrap();
+        assert_eq!(merged.get("A"), None);
+        assert_eq!(merged.get("B"), Some(&3));
+        assert_eq!(merged.get("C"), Some(&4));
+
+        // Unset keys only apply to the inherited entries
+        assert_eq!(merge_maps(&None, &new, &set_of(&["B"])), new);
+    }
+
+    #[test]
+    fn merged_lists_drop_unset_and_duplicated_items() {
+        let base = Some(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let new = Some(vec!["c".to_string(), "d".to_string()]);
+
+        assert_eq!(
+            merge_lists(&base, &new, &set_of(&["a"])),
+            Some(vec!["b".to_string(), "c".to_string(), "d".to_string()])
+        );
+        assert_eq!(
+            merge_lists(&base, &None, &set_of(&["a", "b", "c"])),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn merged_groups_drop_unset_groups() {
+        assert_eq!(
+            merge_groups(
+                &set_of(&["audio", "video"]),
+                &set_of(&["docker"]),
+                &set_of(&["video"])
+            ),
+            set_of(&["audio", "docker"])
+        );
+
+        // The unset groups only apply to the inherited ones
+        assert_eq!(
+            merge_groups(
+                &set_of(&["audio", "video"]),
+                &set_of(&["video"]),
+                &set_of(&["video"])
+            ),
+            set_of(&["audio", "video"])
+        );
+    }
+
+    #[test]
+    fn merged_envs_from_host_drop_unset_names() {
+        let base = parse_run_config("envFromHost: [NPM_TOKEN, {name: TOKEN, from: HOST_TOKEN}]");
+        let new = parse_run_config("envFromHost: [GH_TOKEN]\nunset: {envFromHost: [TOKEN]}");
+
+        let merged = merge_run_config_layers(&base, &new).unwrap();
+        let names: Vec<&String> = merged
+            .get_env_from_host()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get_name())
+            .collect();
+        assert_eq!(names, vec!["GH_TOKEN", "NPM_TOKEN"]);
+    }
+
+    #[test]
+    fn run_config_layers_apply_unset_blocks() {
+        let base = parse_run_config(
+            "env: {PROXY: x, TZ: UTC}\nports: ['8080:80']\ntmpfs: {/tmp: {}}\nnetwork: bridge",
+        );
+        let new = parse_run_config(
+            "env: {LANG: C}\nunset: {env: [PROXY], ports: ['8080:80'], tmpfs: [/tmp]}",
+        );
+
+        let merged = merge_run_config_layers(&base, &new).unwrap();
+        let env = merged.get_env().as_ref().unwrap();
+        assert_eq!(env.get("PROXY"), None);
+        assert_eq!(env.get("TZ"), Some(&"UTC".to_string()));
+        assert_eq!(env.get("LANG"), Some(&"C".to_string()));
+        assert_eq!(merged.get_ports(), &Some(vec![]));
+        assert_eq!(merged.get_tmpfs().as_ref().map(|t| t.len()), Some(0));
+        assert_eq!(merged.get_network(), &Some("bridge".to_string()));
+    }
+
+    #[test]
+    fn run_config_layers_unset_inherited_ulimits() {
+        let base = parse_run_config("resources: {memory: 2g, ulimits: {nofile: 1024, nproc: 512}}");
+        let new = parse_run_config("unset: {ulimits: [nofile]}");
+
+        let merged = merge_run_config_layers(&base, &new).unwrap();
+        let resources = merged.get_resources().as_ref().unwrap();
+        assert_eq!(resources.get_memory(), &Some("2g".to_string()));
+        assert_eq!(
+            resources
+                .get_ulimits()
+                .as_ref()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["nproc"]
+        );
+    }
+
+    #[test]
+    fn unset_blocks_apply_to_all_the_lower_precedence_layers() {
+        let project = parse_run_config("env: {PROXY: x}");
+        let image = parse_run_config("unset: {env: [PROXY]}");
+        let binary = parse_run_config("env: {LANG: C}");
+
+        let merged =
+            merge_run_config_layers(&merge_run_config_layers(&project, &image), &binary).unwrap();
+        assert_eq!(merged.get_env().as_ref().unwrap().get("PROXY"), None);
+
+        // The higher precedence layers can still define the unset settings
+        let binary = parse_run_config("env: {PROXY: y}");
+        let merged =
+            merge_run_config_layers(&merge_run_config_layers(&project, &image), &binary).unwrap();
+        assert_eq!(
+            merged.get_env().as_ref().unwrap().get("PROXY"),
+            Some(&"y".to_string())
+        );
+    }
+
+    #[test]
+    fn unset_entries_only_remove_a_setting_once() {
+        let project = parse_run_config("env: {PROXY: x, TZ: UTC}");
+        let extended_image = parse_run_config("env: {PROXY: y}");
+        let image = parse_run_config("unset: {env: [PROXY, TZ]}");
+
+        // PROXY is removed from the extended file, and TZ from the project level
+        let merged_image = merge_run_config_layers(&extended_image, &image);
+        assert_eq!(
+            merged_image.as_ref().unwrap().get_unset(),
+            &Some(serde_yaml::from_str("env: [TZ]").unwrap())
+        );
+
+        let merged = merge_run_config_layers(&project, &merged_image).unwrap();
+        let env = merged.get_env().as_ref().unwrap();
+        assert_eq!(env.get("PROXY"), Some(&"x".to_string()));
+        assert_eq!(env.get("TZ"), None);
+    }
+
+    #[test]
+    fn overriding_run_configs_take_precedence_over_every_level() {
+        let images: Option<BTreeMap<String, BTreeMap<String, OCIImageConfig>>> =
+            serde_yaml::from_str(
+                "node:\n  14-buster:\n    runConfig: {env: {NODE_ENV: development}}\n    binaries:\n      node: {}\n      npm: {runConfig: {env: {NODE_ENV: production, TZ: UTC}}}\n",
+            )
+            .unwrap();
+        let overriding_config = parse_run_config("env: {NODE_ENV: test}");
+
+        let merged_images = merge_overriding_run_config(&images, &overriding_config).unwrap();
+        let image_config = &merged_images["node"]["14-buster"];
+        for binary_name in &["node", "npm"] {
+            let binary_config = &image_config.get_binaries().as_ref().unwrap()[*binary_name];
+            let merged = merge_run_configs(
+                &parse_run_config("env: {NODE_ENV: ci}"),
+                image_config.get_run_config(),
+                binary_config.get_run_config(),
+                "id",
+                "node@sha256:00",
+                binary_name,
+            )
+            .unwrap();
+            assert_eq!(
+                merged.get_env().as_ref().unwrap().get("NODE_ENV"),
+                Some(&"test".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn valid_ports_are_kept() {
+        let ports = Some(vec![
+            "8080:80".to_string(),
+            "127.0.0.1:auto:3000".to_string(),
+            "auto:53/udp".to_string(),
+        ]);
+        assert_eq!(
+            validate_ports(ports.clone(), &Some("bridge".to_string()), "test"),
+            ports
+        );
+        assert_eq!(validate_ports(ports.clone(), &None, "test"), ports);
+        assert_eq!(
+            validate_ports(Some(vec![]), &Some("host".to_string()), "test"),
+            Some(vec![])
+        );
+        assert_eq!(
+            validate_ports(None, &Some("none".to_string()), "test"),
+            None
+        );
+    }
+
+    #[test]
+    fn port_mappings_are_parsed() {
+        let port_mapping = parse_port_mapping("127.0.0.1:8080:80/udp").unwrap();
+        assert_eq!(port_mapping.get_host_ip(), &Some("127.0.0.1".to_string()));
+        assert_eq!(port_mapping.get_host_port(), &Some(8080));
+        assert_eq!(port_mapping.get_container_port(), 80);
+        assert_eq!(port_mapping.get_protocol(), "udp");
+
+        let port_mapping = parse_port_mapping("auto:3000").unwrap();
+        assert_eq!(port_mapping.get_host_ip(), &None);
+        assert_eq!(port_mapping.get_host_port(), &None);
+        assert_eq!(port_mapping.get_protocol(), "tcp");
+    }
+
+    #[test]
+    fn invalid_port_mappings_are_rejected() {
+        for port_mapping in &[
+            "80",
+            "8080:80/sctp",
+            "8080:0",
+            "0:80",
+            "70000:80",
+            "8080:auto",
+            ":8080:80",
+            "a:b:c:d",
+            "8080:80/",
+        ] {
+            assert!(
+                parse_port_mapping(port_mapping).is_none(),
+                "{}",
+                port_mapping
+            );
+        }
+    }
+
+    #[test]
+    fn memory_sizes_are_parsed() {
+        assert_eq!(parse_memory_size("1024"), Some(1024));
+        assert_eq!(parse_memory_size("512b"), Some(512));
+        assert_eq!(parse_memory_size("2k"), Some(2 << 10));
+        assert_eq!(parse_memory_size("64m"), Some(64 << 20));
+        assert_eq!(parse_memory_size("2G"), Some(2 << 30));
+    }
+
+    #[test]
+    fn invalid_memory_sizes_are_rejected() {
+        assert_eq!(parse_memory_size(""), None);
+        assert_eq!(parse_memory_size("m"), None);
+        assert_eq!(parse_memory_size("-1m"), None);
+        assert_eq!(parse_memory_size("1.5g"), None);
+        assert_eq!(parse_memory_size("1t"), None);
+        assert_eq!(parse_memory_size("99999999999999999999g"), None);
+        assert_eq!(parse_memory_size("99999999999g"), None);
+    }
+
+    #[test]
+    fn non_ascii_memory_size_suffixes_are_rejected() {
+        assert_eq!(parse_memory_size("1\u{212A}"), None); // KELVIN SIGN
+        assert_eq!(parse_memory_size("\u{212A}"), None);
+        assert_eq!(parse_memory_size("1é"), None);
+    }
+
+    fn list_dir(dir_path: &Path) -> Vec<String> {
+        let mut file_names: Vec<String> = read_dir(dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        file_names.sort();
+        file_names
+    }
+
+    #[test]
+    fn write_atomically_creates_and_replaces_files() {
+        let test_dir = create_test_dir();
+        let filepath = test_dir.join("state.yml");
+
+        write_atomically(&filepath, b"first").unwrap();
+        assert_eq!(read(&filepath).unwrap(), b"first");
+
+        write_atomically(&filepath, b"second").unwrap();
+        assert_eq!(read(&filepath).unwrap(), b"second");
+
+        // No temporary files are left behind
+        assert_eq!(list_dir(&test_dir), vec!["state.yml"]);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomically_removes_the_files_of_killed_writers() {
+        let test_dir = create_test_dir();
+        let filepath = test_dir.join("state.yml");
+
+        // PIDs are never that high, while the init process is always running
+        write(test_dir.join(".state.yml.999999999.tmp"), b"partial").unwrap();
+        write(test_dir.join(".state.yml.1.tmp"), b"partial").unwrap();
+        write(test_dir.join(".other.yml.999999999.tmp"), b"partial").unwrap();
+
+        write_atomically(&filepath, b"contents").unwrap();
+        assert_eq!(read(&filepath).unwrap(), b"contents");
+        assert_eq!(
+            list_dir(&test_dir),
+            vec![".other.yml.999999999.tmp", ".state.yml.1.tmp", "state.yml"]
+        );
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomically_keeps_the_previous_contents_on_errors() {
+        let test_dir = create_test_dir();
+        let filepath = test_dir.join("passwd");
+        write_atomically(&filepath, b"root:x:0:0::/root:/bin/sh\n").unwrap();
+
+        // The file can't be replaced by the renamed temporary file
+        let dir_path = test_dir.join("images");
+        create_dir(&dir_path).unwrap();
+        create_dir(dir_path.join("nested")).unwrap();
+        assert!(write_atomically(&dir_path, b"contents").is_err());
+        assert!(dir_path.is_dir());
+
+        assert!(write_atomically(&test_dir.join("missing").join("passwd"), b"x").is_err());
+        assert!(write_atomically(Path::new("/"), b"x").is_err());
+
+        assert_eq!(read(&filepath).unwrap(), b"root:x:0:0::/root:/bin/sh\n");
+        assert_eq!(list_dir(&test_dir), vec!["images", "passwd"]);
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
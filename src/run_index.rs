@@ -0,0 +1,254 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::collections::BTreeMap;
+use std::fs::{metadata, read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::project_config::{write_atomically, ImageBinaryConfigLock, ProjectConfigLock};
+
+// Some filesystems have a timestamps granularity of 2 seconds, so files can be
+// modified again within that interval without changing their metadata.
+const RACY_INTERVAL_SECS: i64 = 2;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileStamp {
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+impl FileStamp {
+    /// Returns None if the file does not exist (or it's not readable)
+    fn read(filepath: &Path) -> Option<Self> {
+        match metadata(filepath) {
+            Ok(m) => Some(Self {
+                device: m.dev(),
+                inode: m.ino(),
+                size: m.size(),
+                mtime: m.mtime(),
+                mtime_nsec: m.mtime_nsec(),
+                ctime: m.ctime(),
+                ctime_nsec: m.ctime_nsec(),
+            }),
+            Err(_) => None,
+        }
+    }
+
+    fn is_racy(&self, validation_start: i64) -> bool {
+        self.mtime >= validation_start - RACY_INTERVAL_SECS
+            || self.ctime >= validation_start - RACY_INTERVAL_SECS
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceFile {
+    path: PathBuf,
+    stamp: Option<FileStamp>, // None for files that must not exist
+}
+
+/// Compact copy of the validated project state, stored in the volatile
+/// directory so shims can skip reading, hashing and parsing the config files
+/// while none of them changes.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunIndex {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    project_internal_id: String,
+    #[serde(with = "hex")]
+    project_state_hash: Vec<u8>,
+    sources: Vec<SourceFile>,
+    binaries: BTreeMap<String, ImageBinaryConfigLock>,
+}
+
+impl RunIndex {
+    /// Returns None if the index is not available, or any of the files it was
+    /// generated from changed.
+    pub fn load(run_index_path: &PathBuf, profile: Option<&str>) -> Option<Self> {
+        let run_index_bytes = read(run_index_path).ok()?;
+        let run_index = serde_yaml::from_slice::<RunIndex>(&run_index_bytes).ok()?;
+
+        if run_index.profile.as_deref() != profile {
+            return None;
+        }
+        for source in run_index.sources.iter() {
+            if FileStamp::read(&source.path) != source.stamp {
+                return None;
+            }
+        }
+
+        Some(run_index)
+    }
+
+    /// The `source_paths` must be stamped after validating their contents
+    pub fn new(
+        source_paths: Vec<PathBuf>,
+        project_state: &ProjectConfigLock,
+        project_state_hash: &[u8],
+    ) -> Self {
+        Self {
+            profile: project_state.get_profile().clone(),
+            project_internal_id: project_state.get_project_internal_id().clone(),
+            project_state_hash: Vec::from(project_state_hash),
+            sources: source_paths
+                .into_iter()
+                .map(|path| SourceFile {
+                    stamp: FileStamp::read(&path),
+                    path,
+                })
+                .collect(),
+            binaries: project_state
+                .get_binaries_configs()
+                .map(|(name, config)| (name.clone(), config.clone()))
+                .collect(),
+        }
+    }
+
+    /// Persists the index, unless some of the source files were modified after
+    /// `validation_start` (or right before it), as their metadata could stay the
+    /// same after another change. Errors are ignored, the index is just a cache.
+    pub fn save(&self, run_index_path: &Path, validation_start: SystemTime) {
+        let validation_start = match validation_start.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(_) => return,
+        };
+
+        for source in self.sources.iter() {
+            if let Some(stamp) = &source.stamp {
+                if stamp.is_racy(validation_start) {
+                    return;
+                }
+            }
+        }
+
+        if let Ok(run_index_bytes) = serde_yaml::to_vec(self) {
+            let _ = write_atomically(run_index_path, &run_index_bytes);
+        }
+    }
+
+    pub fn get_project_internal_id(&self) -> &String {
+        &self.project_internal_id
+    }
+
+    pub fn get_project_state_hash(&self) -> &Vec<u8> {
+        &self.project_state_hash
+    }
+
+    pub fn get_binary_configuration(&self, binary_name: &str) -> Option<&ImageBinaryConfigLock> {
+        self.binaries.get(binary_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_dir;
+    use std::fs::{remove_dir_all, write, OpenOptions};
+    use std::io::Write;
+
+    fn get_project_state(profile: Option<&str>) -> ProjectConfigLock {
+        let project_state: ProjectConfigLock = serde_yaml::from_str(
+            "projectConfigHash: 00\nprojectInternalId: abc\nimages: {}\nbinaries: {}\n",
+        )
+        .unwrap();
+        project_state.update_profile(profile)
+    }
+
+    /// `RunIndex::save` skips the indexes with recently modified sources
+    fn write_run_index(run_index_path: &PathBuf, run_index: &RunIndex) {
+        write(run_index_path, serde_yaml::to_vec(run_index).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_returns_the_index_while_its_sources_are_unchanged() {
+        let test_dir = create_test_dir();
+        let source_path = test_dir.join("Avatarfile");
+        let run_index_path = test_dir.join("run_index.yml");
+        write(&source_path, "version: 0.16.1\n").unwrap();
+
+        let run_index = RunIndex::new(vec![source_path], &get_project_state(None), &[1, 2]);
+        write_run_index(&run_index_path, &run_index);
+
+        assert_eq!(RunIndex::load(&run_index_path, None), Some(run_index));
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn load_discards_the_index_when_a_source_changes() {
+        let test_dir = create_test_dir();
+        let source_path = test_dir.join("Avatarfile");
+        let run_index_path = test_dir.join("run_index.yml");
+        write(&source_path, "version: 0.16.1\n").unwrap();
+
+        let run_index = RunIndex::new(vec![source_path.clone()], &get_project_state(None), &[1, 2]);
+        write_run_index(&run_index_path, &run_index);
+
+        let mut source_file = OpenOptions::new().append(true).open(&source_path).unwrap();
+        source_file.write_all(b"extends: [base.yml]\n").unwrap();
+
+        assert_eq!(RunIndex::load(&run_index_path, None), None);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn load_discards_the_index_when_a_missing_source_appears() {
+        let test_dir = create_test_dir();
+        let missing_path = test_dir.join("Avatarfile.local");
+        let run_index_path = test_dir.join("run_index.yml");
+
+        let run_index = RunIndex::new(
+            vec![missing_path.clone()],
+            &get_project_state(None),
+            &[1, 2],
+        );
+        write_run_index(&run_index_path, &run_index);
+        assert!(RunIndex::load(&run_index_path, None).is_some());
+
+        write(&missing_path, "runConfig: {}\n").unwrap();
+
+        assert_eq!(RunIndex::load(&run_index_path, None), None);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn load_discards_the_index_of_another_profile() {
+        let test_dir = create_test_dir();
+        let run_index_path = test_dir.join("run_index.yml");
+
+        let run_index = RunIndex::new(vec![], &get_project_state(Some("ci")), &[1, 2]);
+        write_run_index(&run_index_path, &run_index);
+
+        assert!(RunIndex::load(&run_index_path, Some("ci")).is_some());
+        assert_eq!(RunIndex::load(&run_index_path, None), None);
+        assert_eq!(RunIndex::load(&run_index_path, Some("dev")), None);
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn save_skips_the_index_when_a_source_was_recently_modified() {
+        let test_dir = create_test_dir();
+        let source_path = test_dir.join("Avatarfile");
+        let run_index_path = test_dir.join("run_index.yml");
+        write(&source_path, "version: 0.16.1\n").unwrap();
+
+        let run_index = RunIndex::new(vec![source_path], &get_project_state(None), &[1, 2]);
+        run_index.save(&run_index_path, SystemTime::now());
+
+        assert!(!run_index_path.exists());
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -12,6 +12,8 @@ use crate::{
     project_config::{save_config, ProjectConfig},
 };
 
+const GITIGNORE_ENTRIES: [&str; 2] = [".avatar-cli/volatile/", ".avatar-cli/Avatarfile.local"];
+
 pub(crate) fn init_subcommand(project_path: &PathBuf) {
     if let Some(p) = get_project_path() {
         eprintln!(
@@ -77,9 +79,27 @@ fn patch_gitignore(project_path: &PathBuf) {
             }
         };
 
-        if !String::from_utf8_lossy(&gitignore_bytes).contains(".avatar-cli/volatile") {
+        let gitignore_contents = String::from_utf8_lossy(&gitignore_bytes).to_string();
+        let missing_entries: Vec<&str> = GITIGNORE_ENTRIES
+            .iter()
+            .filter(|entry| !gitignore_contents.contains(entry.trim_end_matches('/')))
+            .cloned()
+            .collect();
+
+        if !missing_entries.is_empty() {
+            // The section header is only added once, even if new entries appear
+            // in later versions.
+            if !gitignore_contents
+                .lines()
+                .any(|line| line.trim() == "# Avatar-CLI")
+            {
+                gitignore_bytes.extend(b"\n# Avatar-CLI\n");
+            } else if !gitignore_contents.ends_with('\n') {
+                gitignore_bytes.extend(b"\n");
+            }
+
             // TODO: Optimize this, just append, instead of rewriting the entire file
-            gitignore_bytes.extend("\n# Avatar-CLI\n.avatar-cli/volatile/\n".as_bytes());
+            gitignore_bytes.extend(format!("{}\n", missing_entries.join("\n")).as_bytes());
             if let Err(e) = write(&gitignore_path, gitignore_bytes) {
                 eprintln!(
                     "Unable to modify .gitignore file due to unknown reasons.\n\n{}\n",
@@ -95,7 +115,7 @@ fn patch_gitignore(project_path: &PathBuf) {
 
         if let Err(e) = write(
             &gitignore_path,
-            "# Avatar-CLI\n.avatar-cli/volatile/\n".as_bytes(),
+            format!("# Avatar-CLI\n{}\n", GITIGNORE_ENTRIES.join("\n")).as_bytes(),
         ) {
             eprintln!(
                 "Unable to create .gitignore file due to unknown reasons.\n\n{}\n",
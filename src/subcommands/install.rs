@@ -5,47 +5,77 @@
  */
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env,
-    fs::{create_dir_all, remove_dir_all, write},
-    os::unix::fs::symlink,
-    path::PathBuf,
-    process::{exit, Command},
+    fs::{create_dir_all, read_dir, read_link, remove_file, rename, File},
+    os::unix::{fs::symlink, io::AsRawFd},
+    path::{Path, PathBuf},
+    process::{self, exit, Command, Output},
     str::from_utf8,
 };
 
 use duct::cmd;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
 use ring::digest::{digest, Digest, SHA256};
 
 use crate::{
     avatar_env::SESSION_TOKEN,
     directories::{
-        get_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
-        CONTAINER_HOME_PATH, STATEFILE_NAME, VOLATILE_DIR_NAME,
+        get_project_path, get_state_file_name, AVATARFILE_LOCAL_NAME, AVATARFILE_LOCK_NAME,
+        AVATARFILE_NAME, CONFIG_DIR_NAME, CONTAINER_HOME_PATH, VOLATILE_DIR_NAME,
     },
     project_config::{
-        get_config, get_config_lock, merge_run_configs, save_config_lock, ImageBinaryConfigLock,
+        get_config, get_local_config, merge_local_config, merge_profile, merge_run_configs,
+        read_config_lock, save_config_lock, write_atomically, ContainerUser, ImageBinaryConfigLock,
         OCIContainerRunConfig, OCIImageConfig, OCIImageConfigLock, ProjectConfig,
-        ProjectConfigLock, VolumeConfigLock,
+        ProjectConfigFragment, ProjectConfigLock, VolumeConfigLock,
     },
+    warm_containers::prune_orphan_warm_containers,
 };
 
-fn change_volume_permissions(volume_name: &str, container_path: &PathBuf) {
+/// Volumes are owned by the user that runs the binary. For the `image` user,
+/// the chown command is executed inside the binary's image so user names can be
+/// resolved.
+fn change_volume_permissions(
+    volume_name: &str,
+    container_path: &Path,
+    binary_config: &ImageBinaryConfigLock,
+) {
+    let (owner, helper_image) = match binary_config.get_container_user() {
+        ContainerUser::Host => (
+            format!("{}:{}", nix::unistd::getuid(), nix::unistd::getgid()),
+            "alpine:3.12".to_string(),
+        ),
+        ContainerUser::Id(uid, gid) => (format!("{}:{}", uid, gid), "alpine:3.12".to_string()),
+        ContainerUser::Root => return, // Volumes are owned by root by default
+        ContainerUser::Image => {
+            let image_ref = format!(
+                "{}@sha256:{}",
+                binary_config.get_oci_image_name(),
+                binary_config.get_oci_image_hash()
+            );
+            match get_image_user(&image_ref) {
+                Some(image_user) => (image_user, image_ref),
+                None => return,
+            }
+        }
+    };
+
     match Command::new("docker")
         .args(&[
             "run",
             "--rm",
+            "--user",
+            "0:0",
+            "--entrypoint",
+            "chown",
             "--volume",
             &format!("{}:{}", volume_name, container_path.display()),
-            "alpine:3.12",
-            "sh",
-            "-c",
-            &format!(
-                "chown -R {}:{} {}",
-                nix::unistd::getuid(),
-                nix::unistd::getgid(),
-                container_path.display()
-            ),
+            &helper_image,
+            "-R",
+            &owner,
+            &container_path.display().to_string(),
         ])
         .output()
     {
@@ -66,8 +96,48 @@ fn change_volume_permissions(volume_name: &str, container_path: &PathBuf) {
     }
 }
 
+/// Returns the user defined in the image config, if it's not root
+fn get_image_user(image_ref: &str) -> Option<String> {
+    match Command::new("docker")
+        .args(["inspect", "--format={{.Config.User}}", image_ref])
+        .output()
+    {
+        Ok(output) if output.status.success() => match from_utf8(&output.stdout) {
+            Ok(stdout) => match stdout.trim() {
+                "" | "root" | "0" | "0:0" | "root:root" => None,
+                image_user => Some(image_user.to_string()),
+            },
+            Err(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// Explicit uid:gid pairs used by the binaries of each image, they need their
+/// own passwd entries.
+fn get_images_explicit_users(
+    project_state: &ProjectConfigLock,
+) -> BTreeMap<String, BTreeSet<(u32, u32)>> {
+    let mut images_users: BTreeMap<String, BTreeSet<(u32, u32)>> = BTreeMap::new();
+
+    for (_, binary_config) in project_state.get_binaries_configs() {
+        if let ContainerUser::Id(uid, gid) = binary_config.get_container_user() {
+            images_users
+                .entry(format!(
+                    "{}@sha256:{}",
+                    binary_config.get_oci_image_name(),
+                    binary_config.get_oci_image_hash()
+                ))
+                .or_default()
+                .insert((uid, gid));
+        }
+    }
+
+    images_users
+}
+
 fn check_etc_passwd_files(
-    volatile_path: &PathBuf,
+    volatile_path: &Path,
     project_state: &ProjectConfigLock,
     changed_state: bool,
 ) {
@@ -76,10 +146,7 @@ fn check_etc_passwd_files(
         return;
     }
 
-    let images_path = match recreate_volatile_subdir(volatile_path, "images", changed_state) {
-        Some(_images_path) => _images_path,
-        None => return,
-    };
+    let images_path = ensure_volatile_subdir(volatile_path, "images");
 
     let project_internal_id = project_state.get_project_internal_id();
     let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
@@ -93,12 +160,26 @@ fn check_etc_passwd_files(
         }
     };
 
+    let images_explicit_users = get_images_explicit_users(project_state);
+    let no_explicit_users: BTreeSet<(u32, u32)> = BTreeSet::new();
+
     let mut errors = false;
     for (image_name, image_tags) in project_state.get_images() {
         for (image_tag, image_config) in image_tags {
             let image_hash = image_config.get_hash();
             let image_ref = format!("{}@sha256:{}", image_name, image_hash);
             let image_config_path = images_path.join(&image_ref);
+            let local_etc_passwd_path = image_config_path.join("passwd");
+            if !changed_state && local_etc_passwd_path.is_file() {
+                continue;
+            }
+
+            let explicit_users: Vec<&(u32, u32)> = images_explicit_users
+                .get(&image_ref)
+                .unwrap_or(&no_explicit_users)
+                .iter()
+                .filter(|(explicit_uid, _)| *explicit_uid != uid.as_raw())
+                .collect();
 
             if create_dir_all(&image_config_path).is_err() {
                 eprintln!("Unable to create directory {}", image_config_path.display());
@@ -195,16 +276,22 @@ fn check_etc_passwd_files(
                 "/bin/sh"
             };
 
-            let local_etc_passwd_path = image_config_path.join("passwd");
             if !found_passwd {
-                if let Err(e) = write(
-                    &local_etc_passwd_path,
-                    format!(
-                        "{}:x:{}:{}::{}:{}\n",
-                        username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
-                    )
-                    .as_bytes(),
-                ) {
+                let mut passwd_dst_contents = format!(
+                    "{}:x:{}:{}::{}:{}\n",
+                    username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
+                );
+                for (explicit_uid, explicit_gid) in &explicit_users {
+                    passwd_dst_contents.push_str(&get_explicit_user_passwd_line(
+                        *explicit_uid,
+                        *explicit_gid,
+                        inferred_passwd_shell,
+                    ));
+                }
+
+                if let Err(e) =
+                    write_atomically(&local_etc_passwd_path, passwd_dst_contents.as_bytes())
+                {
                     eprintln!(
                         "Unable to create custom passwd file for {}\n\n{}\n",
                         &image_ref,
@@ -231,12 +318,14 @@ fn check_etc_passwd_files(
                 };
 
                 let mut found_user_line = false;
+                let mut found_uids: BTreeSet<String> = BTreeSet::new();
                 let mut passwd_dst_contents = String::with_capacity(passwd_src_contents.len());
 
                 for user_line in passwd_src_contents.lines() {
                     let trimmed_user_line = user_line.trim();
                     let mut user_line_parts = trimmed_user_line.split(':');
                     if let Some(passwd_uid) = user_line_parts.nth(2) {
+                        found_uids.insert(passwd_uid.to_string());
                         if passwd_uid == uid.to_string() {
                             let passwd_shell = match user_line_parts.last() {
                                 Some(_passwd_shell) => _passwd_shell,
@@ -264,7 +353,18 @@ fn check_etc_passwd_files(
                         username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
                     ))
                 }
-                if let Err(e) = write(&local_etc_passwd_path, passwd_dst_contents.as_bytes()) {
+                for (explicit_uid, explicit_gid) in &explicit_users {
+                    if !found_uids.contains(&explicit_uid.to_string()) {
+                        passwd_dst_contents.push_str(&get_explicit_user_passwd_line(
+                            *explicit_uid,
+                            *explicit_gid,
+                            inferred_passwd_shell,
+                        ));
+                    }
+                }
+                if let Err(e) =
+                    write_atomically(&local_etc_passwd_path, passwd_dst_contents.as_bytes())
+                {
                     eprintln!(
                         "Unable to create custom passwd file for {}\n\n{}\n",
                         &image_ref,
@@ -301,19 +401,83 @@ fn check_etc_passwd_files(
     }
 }
 
+fn get_explicit_user_passwd_line(uid: u32, gid: u32, shell: &str) -> String {
+    format!(
+        "avatar-{}:x:{}:{}::{}:{}\n",
+        uid, uid, gid, CONTAINER_HOME_PATH, shell
+    )
+}
+
 fn check_managed_volumes_availability(project_state: &ProjectConfigLock) {
+    check_managed_volumes_owners(project_state);
+
     for (_, binary_config) in project_state.get_binaries_configs() {
         if let Some(run_config) = binary_config.get_run_config() {
             if let Some(volume_configs) = run_config.get_volumes() {
                 volume_configs.iter().for_each(|vc| {
-                    check_managed_volume_existence(vc, project_state.get_project_internal_id())
+                    check_managed_volume_existence(
+                        vc,
+                        binary_config,
+                        project_state.get_project_internal_id(),
+                    )
                 });
             }
         }
     }
 }
 
-fn check_managed_volume_existence(volume_config: &VolumeConfigLock, project_internal_id: &str) {
+/// Volumes are owned by the user running the binary that creates them, so the
+/// binaries sharing a volume (through its `name`) must run as the same user.
+fn check_managed_volumes_owners(project_state: &ProjectConfigLock) {
+    let mut volume_owners: BTreeMap<&str, (String, &str)> = BTreeMap::new();
+
+    for (binary_name, binary_config) in project_state.get_binaries_configs() {
+        let owner = match binary_config.get_container_user() {
+            ContainerUser::Host => "host".to_string(),
+            ContainerUser::Root => "root".to_string(),
+            ContainerUser::Image => format!(
+                "image ({}@sha256:{})",
+                binary_config.get_oci_image_name(),
+                binary_config.get_oci_image_hash()
+            ),
+            ContainerUser::Id(uid, gid) => format!("{}:{}", uid, gid),
+        };
+
+        let volume_configs = match binary_config.get_run_config() {
+            Some(run_config) => match run_config.get_volumes() {
+                Some(volume_configs) => volume_configs,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        for volume_config in volume_configs {
+            match volume_owners.get(volume_config.get_name().as_str()) {
+                Some((other_owner, other_binary_name)) if other_owner != &owner => {
+                    eprintln!(
+                        "The volume '{}' is shared by the binaries '{}' (user: {}) and '{}' (user: {}), but they must run as the same user",
+                        volume_config.get_name(),
+                        other_binary_name,
+                        other_owner,
+                        binary_name,
+                        owner
+                    );
+                    exit(exitcode::DATAERR)
+                }
+                Some(_) => {}
+                None => {
+                    volume_owners.insert(volume_config.get_name(), (owner.clone(), binary_name));
+                }
+            }
+        }
+    }
+}
+
+fn check_managed_volume_existence(
+    volume_config: &VolumeConfigLock,
+    binary_config: &ImageBinaryConfigLock,
+    project_internal_id: &str,
+) {
     match Command::new("docker")
         .args(&["volume", "inspect", volume_config.get_name()])
         .output()
@@ -323,6 +487,7 @@ fn check_managed_volume_existence(volume_config: &VolumeConfigLock, project_inte
                 create_volume(
                     volume_config.get_name(),
                     volume_config.get_container_path(),
+                    binary_config,
                     project_internal_id,
                 );
             }
@@ -386,29 +551,49 @@ fn check_oci_images_availability(project_state: &ProjectConfigLock, show_output:
 fn check_project_settings(
     config_path: &PathBuf,
     config_lock_path: &PathBuf,
+    local_config_path: &PathBuf,
     project_state_path: &PathBuf,
+    profile: Option<&str>,
     show_output: bool,
 ) -> (ProjectConfigLock, bool) {
     let mut changed_state = false;
-    let (config, config_hash) = get_config(&config_path);
+    let (config, config_hash) = get_config(config_path, &mut Vec::new());
+    let local_config = get_local_config(local_config_path, &mut Vec::new());
+    let local_config_hash = local_config
+        .as_ref()
+        .map(|(_, _local_config_hash)| hex::encode(_local_config_hash));
 
     let (config_lock, config_lock_hash) = match config_lock_path.exists() {
         true => {
             if !config_lock_path.is_file() {
                 eprintln!(
                     "The path {} must point to a regular file, found something else",
-                    project_state_path.display()
+                    config_lock_path.display()
                 );
                 exit(exitcode::DATAERR)
             }
 
-            let (_config_lock, _config_lock_hash) = get_config_lock(&config_lock_path);
-
-            if config_hash.as_ref() != &_config_lock.get_project_config_hash()[..] {
-                changed_state = true;
-                generate_config_lock(config_lock_path, &config, &config_hash, show_output)
-            } else {
-                (_config_lock, _config_lock_hash)
+            // Malformed lock files (e.g. with merge conflicts) are regenerated
+            // from the Avatarfile, as the state files are.
+            match read_config_lock(config_lock_path) {
+                Ok((_config_lock, _config_lock_hash))
+                    if config_hash.as_ref() == &_config_lock.get_project_config_hash()[..] =>
+                {
+                    (_config_lock, _config_lock_hash)
+                }
+                Ok(_) => {
+                    changed_state = true;
+                    generate_config_lock(config_lock_path, &config, &config_hash, show_output)
+                }
+                Err(error_msg) => {
+                    eprintln!(
+                        "{}\nRegenerating {}\n",
+                        error_msg,
+                        config_lock_path.display()
+                    );
+                    changed_state = true;
+                    generate_config_lock(config_lock_path, &config, &config_hash, show_output)
+                }
             }
         }
         false => {
@@ -427,13 +612,27 @@ fn check_project_settings(
                 exit(exitcode::DATAERR)
             }
 
-            let (_project_state, _) = get_config_lock(&project_state_path);
-
-            if config_lock_hash.as_ref() != &_project_state.get_project_config_hash()[..] {
-                changed_state = true;
-                update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())
-            } else {
-                _project_state
+            // The state file is regenerated when it's malformed, as it's volatile
+            match read_config_lock(project_state_path) {
+                Ok((_project_state, _))
+                    if config_lock_hash.as_ref()
+                        == &_project_state.get_project_config_hash()[..]
+                        && &local_config_hash == _project_state.get_local_config_hash()
+                        && _project_state.get_profile().as_deref() == profile =>
+                {
+                    _project_state
+                }
+                _ => {
+                    changed_state = true;
+                    generate_project_state(
+                        &config,
+                        &local_config,
+                        config_lock,
+                        config_lock_hash.as_ref(),
+                        profile,
+                        show_output,
+                    )
+                }
             }
         }
         false => {
@@ -445,7 +644,14 @@ fn check_project_settings(
                 exit(exitcode::CANTCREAT)
             }
 
-            update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())
+            generate_project_state(
+                &config,
+                &local_config,
+                config_lock,
+                config_lock_hash.as_ref(),
+                profile,
+                show_output,
+            )
         }
     };
 
@@ -453,7 +659,10 @@ fn check_project_settings(
 }
 
 fn compile_image_configs(
-    (image_name, image_tags, show_output): (&String, &BTreeMap<String, OCIImageConfig>, bool),
+    image_name: &str,
+    image_tags: &BTreeMap<String, OCIImageConfig>,
+    pinned_image_tags: Option<&BTreeMap<String, OCIImageConfigLock>>,
+    show_output: bool,
 ) -> (String, BTreeMap<String, OCIImageConfigLock>) {
     if image_tags.is_empty() {
         eprintln!("No tags are defined for image {}", image_name);
@@ -461,23 +670,48 @@ fn compile_image_configs(
     }
 
     (
-        image_name.clone(),
+        image_name.to_string(),
         image_tags
             .iter()
             .map(|(image_tag, image_config)| {
-                (
-                    image_tag,
-                    format!("{}:{}", image_name, image_tag),
-                    image_config.get_run_config().clone(),
-                    show_output,
-                )
+                // Images already pinned by the lock file must keep their hash
+                match pinned_image_tags.and_then(|pinned_tags| pinned_tags.get(image_tag)) {
+                    Some(pinned_image_config) => (
+                        image_tag.clone(),
+                        OCIImageConfigLock::new(
+                            pinned_image_config.get_hash().clone(),
+                            image_config.get_run_config().clone(),
+                            match pinned_image_config.get_env_path() {
+                                Some(env_path) => Some(env_path.clone()),
+                                None => get_image_env_path(
+                                    &format!(
+                                        "{}@sha256:{}",
+                                        image_name,
+                                        pinned_image_config.get_hash()
+                                    ),
+                                    show_output,
+                                ),
+                            },
+                        ),
+                    ),
+                    None => get_image_config_by_tag((
+                        image_tag,
+                        format!("{}:{}", image_name, image_tag),
+                        image_config.get_run_config().clone(),
+                        show_output,
+                    )),
+                }
             })
-            .map(get_image_config_by_tag)
             .collect(),
     )
 }
 
-fn create_volume(volume_name: &str, container_path: &PathBuf, project_internal_id: &str) {
+fn create_volume(
+    volume_name: &str,
+    container_path: &Path,
+    binary_config: &ImageBinaryConfigLock,
+    project_internal_id: &str,
+) {
     let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
 
     match Command::new("docker")
@@ -498,7 +732,7 @@ fn create_volume(volume_name: &str, container_path: &PathBuf, project_internal_i
                 exit(exitcode::SOFTWARE);
             }
 
-            change_volume_permissions(volume_name, container_path)
+            change_volume_permissions(volume_name, container_path, binary_config)
         }
         Err(e) => {
             eprintln!(
@@ -517,7 +751,7 @@ fn generate_config_lock(
     config_hash: &Digest,
     show_output: bool,
 ) -> (ProjectConfigLock, Digest) {
-    let image_configs = get_image_compiled_configs(config, show_output);
+    let image_configs = get_image_compiled_configs(config, None, show_output);
     let binaries_settings = get_binaries_settings(config, &image_configs);
 
     let config_lock = ProjectConfigLock::new(
@@ -571,11 +805,13 @@ fn get_binaries_settings(
                                 ImageBinaryConfigLock::new(
                                     image_name.clone(),
                                     image_config.get_hash().clone(),
+                                    image_config.get_env_path().clone(),
                                     binary_config
                                         .get_path()
                                         .clone()
                                         .unwrap_or(PathBuf::from(binary_name)),
                                     merge_run_configs(
+                                        config.get_run_config(),
                                         image_config.get_run_config(),
                                         binary_config.get_run_config(),
                                         config.get_project_internal_id(),
@@ -597,13 +833,19 @@ fn get_binaries_settings(
 
 fn get_image_compiled_configs(
     config: &ProjectConfig,
+    pinned_images: Option<&BTreeMap<String, BTreeMap<String, OCIImageConfigLock>>>,
     show_output: bool,
 ) -> BTreeMap<String, BTreeMap<String, OCIImageConfigLock>> {
     match config.get_images() {
         Some(images) => images
             .iter()
             .map(|(image_name, image_tags)| {
-                compile_image_configs((image_name, image_tags, show_output))
+                compile_image_configs(
+                    image_name,
+                    image_tags,
+                    pinned_images.and_then(|_pinned_images| _pinned_images.get(image_name)),
+                    show_output,
+                )
             })
             .collect(),
         None => BTreeMap::new(),
@@ -627,7 +869,11 @@ fn get_image_config_by_tag(
                 Ok(stdout) => match stdout.trim().split(':').nth(1) {
                     Some(hash) => (
                         image_tag.clone(),
-                        OCIImageConfigLock::new(hash.to_string(), run_config),
+                        OCIImageConfigLock::new(
+                            hash.to_string(),
+                            run_config,
+                            get_image_env_path(&image_fqn, show_output),
+                        ),
                     ),
                     None => {
                         eprintln!("The command `docker inspect --format='{{index .RepoDigests 0}}' {}` returned an unexpected output", image_fqn);
@@ -655,14 +901,68 @@ fn get_image_config_by_tag(
     }
 }
 
+/// Retrieves the PATH environment variable defined in the image config, so it
+/// can be extended (instead of replaced) when running the containers. Images
+/// that are not available yet (e.g. pinned by the lock file in a fresh
+/// checkout) are pulled first. Returns None if the image does not define PATH.
+fn get_image_env_path(image_ref: &str, show_output: bool) -> Option<String> {
+    let inspect_format = "--format={{range .Config.Env}}{{println .}}{{end}}";
+
+    let mut inspect_output = inspect_image(image_ref, inspect_format);
+    if !inspect_output.status.success() {
+        pull_oci_image_by_fqn(image_ref, show_output);
+        inspect_output = inspect_image(image_ref, inspect_format);
+    }
+
+    if !inspect_output.status.success() {
+        eprintln!(
+            "Unable to inspect the config of the OCI image {}\n\n{}\n",
+            image_ref,
+            String::from_utf8_lossy(&inspect_output.stderr).trim()
+        );
+        exit(exitcode::UNAVAILABLE)
+    }
+
+    match from_utf8(&inspect_output.stdout) {
+        Ok(stdout) => stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("PATH="))
+            .map(String::from),
+        Err(e) => {
+            eprintln!(
+                "The command `docker inspect '{}' {}` returned an unexpected output.\n\n{}\n",
+                inspect_format, image_ref, e
+            );
+            exit(exitcode::PROTOCOL)
+        }
+    }
+}
+
+fn inspect_image(image_ref: &str, inspect_format: &str) -> Output {
+    match Command::new("docker")
+        .args(["inspect", inspect_format, image_ref])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!(
+                "Unknow error while trying to inspect OCI image {}:\n\n{}\n",
+                image_ref, e
+            );
+            exit(exitcode::OSERR)
+        }
+    }
+}
+
 pub(crate) fn install_subcommand(
     show_output: bool,
+    profile: Option<&str>,
 ) -> (PathBuf, PathBuf, PathBuf, PathBuf, ProjectConfigLock) {
     if let Ok(session_token) = env::var(SESSION_TOKEN) {
         eprintln!(
-        "You are already in an Avatar CLI session (with token '{}').\nIf the environment changed, consider typing 'exit' and trying again.",
-        session_token
-    );
+            "You are already in an Avatar CLI session (with token '{}').\nIf the environment changed, consider typing 'exit' and trying again.",
+            session_token
+        );
         exit(exitcode::USAGE)
     }
 
@@ -674,32 +974,88 @@ pub(crate) fn install_subcommand(
         }
     };
 
+    install_project(project_path, show_output, profile)
+}
+
+/// Regenerates the outdated (or missing) lock & state files, without any output.
+/// When several terminals do it at once, the later ones find the files updated.
+/// The new images, volumes, shims & passwd files are set up too, as the volatile
+/// directory is only updated in place (it's in use by the running tools).
+pub(crate) fn refresh_project_state(project_path: &Path, profile: Option<&str>) {
+    install_project(project_path.to_path_buf(), false, profile);
+}
+
+/// Quietly sets the project up for the `avatar run` calls made outside of any
+/// session, regardless of `autoRefresh`, as nothing else would do it.
+pub(crate) fn bootstrap_project(project_path: &Path, profile: Option<&str>) {
+    install_project(project_path.to_path_buf(), false, profile);
+}
+
+/// Checks (with a single docker call) that the binary's image & volumes exist,
+/// as they could have been removed since the project was set up.
+pub(crate) fn is_binary_set_up(binary_config: &ImageBinaryConfigLock) -> bool {
+    let mut object_names = vec![format!(
+        "{}@sha256:{}",
+        binary_config.get_oci_image_name(),
+        binary_config.get_oci_image_hash()
+    )];
+    if let Some(volume_configs) = binary_config
+        .get_run_config()
+        .as_ref()
+        .and_then(|run_config| run_config.get_volumes().as_ref())
+    {
+        object_names.extend(volume_configs.iter().map(|vc| vc.get_name().clone()));
+    }
+
+    match Command::new("docker")
+        .args(["inspect", "--format", "{{.Id}}"])
+        .args(&object_names)
+        .output()
+    {
+        Ok(output) => output.status.success(),
+        Err(_) => false,
+    }
+}
+
+fn install_project(
+    project_path: PathBuf,
+    show_output: bool,
+    profile: Option<&str>,
+) -> (PathBuf, PathBuf, PathBuf, PathBuf, ProjectConfigLock) {
     let project_data_path = project_path.join(CONFIG_DIR_NAME);
+    let _project_data_lock = lock_project_data_dir(&project_data_path, show_output);
+
     let config_path = project_data_path.join(AVATARFILE_NAME);
     let config_lock_path = project_data_path.join(AVATARFILE_LOCK_NAME);
+    let local_config_path = project_data_path.join(AVATARFILE_LOCAL_NAME);
     let volatile_path = project_data_path.join(VOLATILE_DIR_NAME);
-    let project_state_path = volatile_path.join(STATEFILE_NAME);
+    let project_state_path = volatile_path.join(get_state_file_name(profile));
 
     let (project_state, changed_state) = check_project_settings(
         &config_path,
         &config_lock_path,
+        &local_config_path,
         &project_state_path,
+        profile,
         show_output,
     );
     let pulled_oci_images = check_oci_images_availability(&project_state, show_output);
     check_managed_volumes_availability(&project_state);
-    populate_volatile_bin_dir(
-        &volatile_path,
-        &project_state,
-        pulled_oci_images || changed_state,
-    );
-    populate_volatile_home_dir(&volatile_path, pulled_oci_images || changed_state);
+    populate_volatile_bin_dir(&volatile_path, &project_state);
+    ensure_volatile_subdir(&volatile_path, "home");
     check_etc_passwd_files(
         &volatile_path,
         &project_state,
         pulled_oci_images || changed_state,
     );
 
+    // The state is persisted once the volatile directory is ready, so an
+    // interrupted install is completed by the next one.
+    if changed_state {
+        save_config_lock(&project_state_path, &project_state);
+    }
+    prune_orphan_warm_containers(project_state.get_project_internal_id());
+
     (
         project_path,
         config_path,
@@ -707,18 +1063,53 @@ pub(crate) fn install_subcommand(
         project_state_path,
         project_state,
     )
-}
+} // The project data directory is unlocked when `_project_data_lock` is dropped
 
-fn populate_volatile_bin_dir(
-    volatile_path: &PathBuf,
-    project_state: &ProjectConfigLock,
-    changed_state: bool,
-) {
-    let bin_path = match recreate_volatile_subdir(volatile_path, "bin", changed_state) {
-        Some(_bin_path) => _bin_path,
-        None => return,
+/// Takes an advisory lock on the project data directory, to serialize the
+/// installs started from several terminals (or refreshes from the shims).
+fn lock_project_data_dir(project_data_path: &PathBuf, show_output: bool) -> File {
+    let project_data_dir = match File::open(project_data_path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!(
+                "Unable to open directory {}\n\n{}\n",
+                project_data_path.display(),
+                e
+            );
+            exit(exitcode::NOINPUT)
+        }
     };
 
+    let lock_result = match flock(
+        project_data_dir.as_raw_fd(),
+        FlockArg::LockExclusiveNonblock,
+    ) {
+        Err(nix::Error::Sys(Errno::EAGAIN)) => {
+            if show_output {
+                eprintln!("Waiting for another avatar process working on this project...");
+            }
+            flock(project_data_dir.as_raw_fd(), FlockArg::LockExclusive)
+        }
+        result => result,
+    };
+
+    if let Err(e) = lock_result {
+        eprintln!(
+            "Unable to lock directory {}\n\n{}\n",
+            project_data_path.display(),
+            e
+        );
+        exit(exitcode::IOERR)
+    }
+
+    project_data_dir
+}
+
+/// The bin directory is in use by the running sessions, so its links are
+/// replaced one by one (through renames) instead of recreating it.
+fn populate_volatile_bin_dir(volatile_path: &Path, project_state: &ProjectConfigLock) {
+    let bin_path = ensure_volatile_subdir(volatile_path, "bin");
+
     let avatar_path = match env::current_exe() {
         Ok(p) => p,
         Err(e) => {
@@ -730,18 +1121,37 @@ fn populate_volatile_bin_dir(
         }
     };
 
-    for binary_name in project_state.get_binary_names() {
-        if symlink(&avatar_path, bin_path.join(binary_name)).is_err() {
+    let binary_names: BTreeSet<&String> = project_state.get_binary_names().collect();
+
+    if let Ok(bin_entries) = read_dir(&bin_path) {
+        for bin_entry in bin_entries.flatten() {
+            let entry_name = bin_entry.file_name().to_string_lossy().to_string();
+            if !binary_names.contains(&entry_name) && remove_file(bin_entry.path()).is_err() {
+                eprintln!("Unable to remove stale symlink {}", entry_name);
+                exit(exitcode::IOERR)
+            }
+        }
+    }
+
+    for binary_name in binary_names {
+        let link_path = bin_path.join(binary_name);
+        if read_link(&link_path).ok().as_ref() == Some(&avatar_path) {
+            continue;
+        }
+
+        let tmp_link_path = bin_path.join(format!(".{}.{}.tmp", binary_name, process::id()));
+        let _ = remove_file(&tmp_link_path);
+        if symlink(&avatar_path, &tmp_link_path)
+            .and_then(|_| rename(&tmp_link_path, &link_path))
+            .is_err()
+        {
+            let _ = remove_file(&tmp_link_path);
             eprintln!("Unable to create symlink to {} binary", binary_name);
             exit(exitcode::CANTCREAT)
         }
     }
 }
 
-fn populate_volatile_home_dir(volatile_path: &PathBuf, changed_state: bool) {
-    recreate_volatile_subdir(volatile_path, "home", changed_state);
-}
-
 fn pull_oci_image_by_fqn(image_ref: &str, show_output: bool) {
     // This code assumes that the existence of the docker command has been checked before
     if show_output {
@@ -781,34 +1191,17 @@ fn pull_oci_image_by_fqn(image_ref: &str, show_output: bool) {
     }
 }
 
-fn recreate_volatile_subdir(
-    volatile_path: &PathBuf,
-    subdir_name: &str,
-    changed_state: bool,
-) -> Option<PathBuf> {
+/// The volatile subdirectories are never recreated, as they're in use by the
+/// running tools (the home directory is mounted into their containers).
+fn ensure_volatile_subdir(volatile_path: &Path, subdir_name: &str) -> PathBuf {
     let subdir_path = volatile_path.join(subdir_name);
 
-    if subdir_path.exists() {
-        if !subdir_path.is_dir() {
-            eprintln!(
-                "The path {} must be a directory, but found something else",
-                subdir_path.display()
-            );
-            exit(exitcode::USAGE)
-        }
-
-        if !changed_state {
-            return None;
-        }
-
-        if let Err(e) = remove_dir_all(&subdir_path) {
-            eprintln!(
-                "Unable to delete broken directory {}\n\n{}\n",
-                subdir_path.display(),
-                e.to_string()
-            );
-            exit(exitcode::IOERR)
-        }
+    if subdir_path.exists() && !subdir_path.is_dir() {
+        eprintln!(
+            "The path {} must be a directory, but found something else",
+            subdir_path.display()
+        );
+        exit(exitcode::USAGE)
     }
 
     if create_dir_all(&subdir_path).is_err() {
@@ -816,15 +1209,50 @@ fn recreate_volatile_subdir(
         exit(exitcode::CANTCREAT)
     }
 
-    Some(subdir_path)
+    subdir_path
 }
 
-fn update_project_state(
-    project_state_path: &PathBuf,
-    mut project_state: ProjectConfigLock,
+/// The project state is the lock file plus the personal overrides defined in
+/// the local config file and the selected profile, which must never leak into
+/// the lock file. It's persisted by the caller.
+fn generate_project_state(
+    config: &ProjectConfig,
+    local_config: &Option<(ProjectConfigFragment, Digest)>,
+    config_lock: ProjectConfigLock,
     config_lock_hash: &[u8],
+    profile: Option<&str>,
+    show_output: bool,
 ) -> ProjectConfigLock {
-    project_state = project_state.update_project_config_hash(config_lock_hash);
-    save_config_lock(project_state_path, &project_state);
-    project_state
+    match (local_config, profile) {
+        (None, None) => config_lock.update_project_config_hash(config_lock_hash),
+        _ => {
+            let mut state_config = config.clone();
+            if let Some((_local_config, _)) = local_config {
+                state_config = merge_local_config(&state_config, _local_config);
+            }
+            if let Some(profile_name) = profile {
+                state_config = merge_profile(&state_config, profile_name);
+            }
+
+            let image_configs = get_image_compiled_configs(
+                &state_config,
+                Some(config_lock.get_images()),
+                show_output,
+            );
+            let binaries_settings = get_binaries_settings(&state_config, &image_configs);
+
+            ProjectConfigLock::new(
+                Vec::<u8>::from(config_lock_hash),
+                state_config.get_project_internal_id().clone(),
+                image_configs,
+                binaries_settings,
+            )
+        }
+    }
+    .update_local_config_hash(
+        local_config
+            .as_ref()
+            .map(|(_, _local_config_hash)| _local_config_hash.as_ref()),
+    )
+    .update_profile(profile)
 }
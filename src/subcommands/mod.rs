@@ -8,6 +8,8 @@ use std::{env, path::PathBuf, process::exit};
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
+use crate::avatar_env::get_selected_profile;
+
 pub(crate) mod init;
 pub(crate) mod install;
 pub(crate) mod run;
@@ -19,9 +21,13 @@ pub(crate) fn select() {
     let matches = App::new("avatar")
         .version(AVATAR_CLI_VERSION)
         .setting(AppSettings::SubcommandRequired)
-        .subcommand(SubCommand::with_name("export-env").about(
-            "Prints shell variable exports to create a new Avatar-CLI session. Useful for scripts.",
-        ))
+        .subcommand(
+            SubCommand::with_name("export-env")
+                .about(
+                    "Prints shell variable exports to create a new Avatar-CLI session. Useful for scripts.",
+                )
+                .arg(profile_arg()),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .about("It generates a new Avatar-CLI project configuration")
@@ -34,19 +40,27 @@ pub(crate) fn select() {
         )
         .subcommand(
             SubCommand::with_name("install")
-                .about("It 'installs' all the project stated dependencies"),
+                .about("It 'installs' all the project stated dependencies")
+                .arg(profile_arg()),
         )
         .subcommand(
             SubCommand::with_name("shell")
-                .about("Starts a new subshell exposing the wrapped project tools"),
+                .about("Starts a new subshell exposing the wrapped project tools")
+                .arg(profile_arg()),
         )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Executes a wrapped project tool without having to enter into a subshell")
+                .setting(AppSettings::TrailingVarArg)
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(interactivity_arg("interactive", "Keeps stdin open"))
+                .arg(interactivity_arg("tty", "Allocates a pseudo-TTY"))
                 .arg(Arg::with_name("program_name").index(1).required(true))
                 .arg(
                     Arg::with_name("program_args")
+                        .index(2)
                         .multiple(true)
+                        .allow_hyphen_values(true)
                         .required(false),
                 ),
         )
@@ -55,7 +69,12 @@ pub(crate) fn select() {
     match matches.subcommand_name() {
         Some(subcommand_name) => match subcommand_name {
             "avatar" => exit(exitcode::OK),
-            "export-env" => shell::export_env_subcommand(),
+            "export-env" => {
+                let export_env_matches = matches.subcommand_matches("export-env").unwrap();
+                shell::export_env_subcommand(
+                    get_selected_profile(export_env_matches.value_of("profile")).as_deref(),
+                )
+            }
             "init" => {
                 let init_matches = matches.subcommand_matches("init").unwrap();
                 let project_path = match init_matches.value_of("project_path") {
@@ -71,10 +90,19 @@ pub(crate) fn select() {
                 init::init_subcommand(&project_path)
             }
             "install" => {
-                install::install_subcommand(true);
+                let install_matches = matches.subcommand_matches("install").unwrap();
+                install::install_subcommand(
+                    true,
+                    get_selected_profile(install_matches.value_of("profile")).as_deref(),
+                );
+            }
+            "run" => run::run_subcommand(matches.subcommand_matches("run").unwrap()),
+            "shell" => {
+                let shell_matches = matches.subcommand_matches("shell").unwrap();
+                shell::shell_subcommand(
+                    get_selected_profile(shell_matches.value_of("profile")).as_deref(),
+                )
             }
-            "run" => run::run_subcommand(),
-            "shell" => shell::shell_subcommand(),
             _ => {
                 eprintln!("Invalid subcommand");
                 exit(exitcode::USAGE)
@@ -83,3 +111,20 @@ pub(crate) fn select() {
         None => exit(exitcode::SOFTWARE), // This branch should be unreachable
     };
 }
+
+fn interactivity_arg<'a, 'b>(name: &'a str, help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("MODE")
+        .possible_values(&["auto", "always", "never"])
+        .help(help)
+        .required(false)
+}
+
+fn profile_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("profile")
+        .long("profile")
+        .value_name("PROFILE")
+        .help("Selects one of the profiles defined in the project config")
+        .required(false)
+}
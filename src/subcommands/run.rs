@@ -4,24 +4,92 @@
  *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
  */
 
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::{create_dir_all, symlink_metadata};
+use std::io::ErrorKind;
+use std::net::{TcpListener, UdpSocket};
 use std::os::unix::process::CommandExt; // Brings trait that allows us to use exec
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::{
     process::{exit, Command},
     str::from_utf8,
 };
 
+use clap::ArgMatches;
+use nix::sys::stat::{fstat, stat, SFlag};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-use crate::avatar_env::{AvatarEnv, PROCESS_ID, PROJECT_INTERNAL_ID, SESSION_TOKEN};
+use crate::avatar_env::{
+    get_selected_profile, AvatarEnv, INTERACTIVE, PROCESS_ID, PROJECT_INTERNAL_ID, SESSION_TOKEN,
+    TTY,
+};
 use crate::directories::{
-    check_if_inside_project_dir, get_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME,
-    CONFIG_DIR_NAME, CONTAINER_HOME_PATH, STATEFILE_NAME, VOLATILE_DIR_NAME,
+    check_if_inside_project_dir, get_project_path, get_run_index_file_name, get_state_file_name,
+    AVATARFILE_LOCAL_NAME, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+    CONTAINER_HOME_PATH, CONTAINER_PROJECT_PATH, VOLATILE_DIR_NAME,
+};
+use crate::dotenv::read_env_file;
+use crate::interpolation::InterpolationContext;
+use crate::project_config::{
+    get_config, get_local_config, parse_port_mapping, read_config_lock, BindingConfig,
+    ContainerUser, EnvFromHostConfig, ImageBinaryConfigLock, InteractivityMode,
+    OCIContainerRunConfigLock, ProjectMount, ResourcesConfig, SELinuxLabel, SecurityConfig,
+    TmpfsConfig, WorkdirPolicy,
 };
-use crate::project_config::{get_config, get_config_lock, ImageBinaryConfigLock};
+use crate::run_index::RunIndex;
+use crate::subcommands::install::{bootstrap_project, is_binary_set_up, refresh_project_state};
+use crate::supervisor::supervise;
+use crate::warm_containers::{
+    exec_in_warm_container, get_session_owner, is_warm_container_ready, start_warm_container,
+};
+
+// Used for images whose config does not define their own PATH
+const DEFAULT_CONTAINER_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// Variables set by Avatar-CLI itself, patterns in envFromHost never match them
+const RESERVED_ENV_VAR_NAMES: [&str; 4] = ["HOME", "PATH", "USER", "USERNAME"];
+const RESERVED_ENV_VAR_PREFIX: &str = "AVATAR_CLI_";
+
+/// Per-invocation settings, they take precedence over the binaries' run configs
+struct RunOverrides {
+    interactive: Option<InteractivityMode>,
+    tty: Option<InteractivityMode>,
+}
+
+impl RunOverrides {
+    /// Values passed as flags take precedence over the environment variables
+    fn new(interactive: Option<&str>, tty: Option<&str>) -> Self {
+        Self {
+            interactive: Self::get_mode(interactive, INTERACTIVE),
+            tty: Self::get_mode(tty, TTY),
+        }
+    }
+
+    fn get_mode(cli_value: Option<&str>, var_name: &str) -> Option<InteractivityMode> {
+        let value = match cli_value {
+            Some(v) => v.to_string(),
+            None => match env::var(var_name) {
+                Ok(v) if !v.is_empty() => v,
+                _ => return None,
+            },
+        };
+
+        match InteractivityMode::from_name(&value) {
+            Some(mode) => Some(mode),
+            None => {
+                eprintln!(
+                    "Invalid {} value: '{}', allowed values are auto, always and never",
+                    var_name, value
+                );
+                exit(exitcode::USAGE)
+            }
+        }
+    }
+}
 
-pub(crate) fn run_subcommand() {
+pub(crate) fn run_subcommand(run_matches: &ArgMatches) {
     let project_path = match get_project_path() {
         Some(p) => p,
         None => {
@@ -30,20 +98,42 @@ pub(crate) fn run_subcommand() {
         }
     };
 
-    let used_program_name = match env::args().nth(2) {
+    let used_program_name = match run_matches.value_of("program_name") {
         Some(n) => n,
         None => {
             eprintln!("A program name must be passed to 'avatar run'");
             exit(exitcode::USAGE)
         }
     };
+    let program_args: Vec<String> = match run_matches.values_of("program_args") {
+        Some(values) => values.map(String::from).collect(),
+        None => Vec::new(),
+    };
 
-    let session_token = match env::var(SESSION_TOKEN) {
-        Ok(st) => st,
-        Err(_) => thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+    let profile = get_selected_profile(None);
+
+    // Outside the sessions (e.g. in CI pipelines, or on fresh checkouts) there
+    // may be no previous install, so the project is set up when it's needed.
+    let (session_token, outside_session) = match env::var(SESSION_TOKEN) {
+        Ok(st) => (st, false),
+        Err(_) => (
+            thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+            true,
+        ),
     };
 
-    run(&project_path, &used_program_name, &session_token, 4)
+    run(
+        &project_path,
+        used_program_name,
+        program_args,
+        &session_token,
+        outside_session,
+        profile.as_deref(),
+        RunOverrides::new(
+            run_matches.value_of("interactive"),
+            run_matches.value_of("tty"),
+        ),
+    )
 }
 
 pub(crate) fn run_in_subshell_mode(used_program_name: &str) {
@@ -53,12 +143,23 @@ pub(crate) fn run_in_subshell_mode(used_program_name: &str) {
     run(
         project_path,
         used_program_name,
+        env::args().skip(1).collect(),
         project_env.get_session_token(),
-        1,
+        false,
+        project_env.get_profile().as_deref(),
+        RunOverrides::new(None, None),
     );
 }
 
-fn run(project_path: &PathBuf, used_program_name: &str, session_token: &str, skip_args: usize) {
+fn run(
+    project_path: &PathBuf,
+    used_program_name: &str,
+    program_args: Vec<String>,
+    session_token: &str,
+    outside_session: bool,
+    profile: Option<&str>,
+    run_overrides: RunOverrides,
+) {
     let current_dir = match env::current_dir() {
         Ok(p) => p,
         Err(_) => {
@@ -69,6 +170,67 @@ fn run(project_path: &PathBuf, used_program_name: &str, session_token: &str, ski
 
     check_if_inside_project_dir(project_path, &current_dir);
 
+    let volatile_path = project_path.join(CONFIG_DIR_NAME).join(VOLATILE_DIR_NAME);
+    let run_index_path = volatile_path.join(get_run_index_file_name(profile));
+
+    // The outdated files are refreshed by `validate_project_state` (unless
+    // `autoRefresh` is disabled), while the missing ones require a full install
+    let needs_bootstrap = outside_session
+        && (!project_path
+            .join(CONFIG_DIR_NAME)
+            .join(AVATARFILE_LOCK_NAME)
+            .is_file()
+            || !volatile_path.join(get_state_file_name(profile)).is_file());
+    if needs_bootstrap {
+        bootstrap_project(project_path, profile);
+    }
+    let run_index = match RunIndex::load(&run_index_path, profile) {
+        Some(run_index) => run_index,
+        None => {
+            let validation_start = SystemTime::now();
+            let run_index = validate_project_state(project_path, profile, true);
+            run_index.save(&run_index_path, validation_start);
+            run_index
+        }
+    };
+
+    let binary_configuration = match run_index.get_binary_configuration(used_program_name) {
+        Some(c) => c,
+        None => {
+            eprintln!(
+                "Binary '{}' not properly configured in lock file '{}'",
+                used_program_name,
+                volatile_path.join(get_state_file_name(profile)).display()
+            );
+            exit(1)
+        }
+    };
+
+    if outside_session && !needs_bootstrap && !is_binary_set_up(binary_configuration) {
+        bootstrap_project(project_path, profile);
+    }
+
+    run_docker_command(
+        binary_configuration,
+        &current_dir,
+        project_path,
+        &run_index,
+        session_token,
+        program_args,
+        run_overrides,
+    );
+}
+
+/// Checks that the project state is up to date with respect to the config
+/// files, returning the index of the validated state. Outdated files are
+/// regenerated when `allow_refresh` is set, unless `autoRefresh` is disabled.
+fn validate_project_state(
+    project_path: &PathBuf,
+    profile: Option<&str>,
+    allow_refresh: bool,
+) -> RunIndex {
+    let mut source_paths: Vec<PathBuf> = Vec::new();
+
     let config_path = project_path.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
     if !config_path.exists() || !config_path.is_file() {
         eprintln!("The config file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", config_path.display());
@@ -86,104 +248,198 @@ fn run(project_path: &PathBuf, used_program_name: &str, session_token: &str, ski
     let project_state_path = project_path
         .join(CONFIG_DIR_NAME)
         .join(VOLATILE_DIR_NAME)
-        .join(STATEFILE_NAME);
+        .join(get_state_file_name(profile));
     if !project_state_path.exists() || !project_state_path.is_file() {
         eprintln!("The project state file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", project_state_path.display());
         exit(exitcode::NOINPUT)
     }
 
-    let (_, config_hash) = get_config(&config_path);
-    let (config_lock, config_lock_hash) = get_config_lock(&config_lock_path);
+    let local_config_path = project_path
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_LOCAL_NAME);
+
+    let (config, config_hash) = get_config(&config_path, &mut source_paths);
+    let auto_refresh = allow_refresh && config.get_auto_refresh() != &Some(false);
+    let ((config_lock, config_lock_hash), (project_state, project_state_hash)) = match (
+        read_config_lock(&config_lock_path),
+        read_config_lock(&project_state_path),
+    ) {
+        (Ok(config_lock), Ok(project_state)) => (config_lock, project_state),
+        (config_lock, project_state) => {
+            if auto_refresh {
+                refresh_project_state(project_path, profile);
+                return validate_project_state(project_path, profile, false);
+            }
+            eprintln!("{}", config_lock.err().or(project_state.err()).unwrap());
+            exit(exitcode::DATAERR)
+        }
+    };
+    let local_config_hash = get_local_config(&local_config_path, &mut source_paths)
+        .map(|(_, _local_config_hash)| hex::encode(_local_config_hash));
+
+    let outdated_file = if config_hash.as_ref() != &config_lock.get_project_config_hash()[..] {
+        Some((&config_path, &config_lock_path))
+    } else if config_lock_hash.as_ref() != &project_state.get_project_config_hash()[..] {
+        Some((&config_lock_path, &project_state_path))
+    } else if &local_config_hash != project_state.get_local_config_hash() {
+        Some((&local_config_path, &project_state_path))
+    } else {
+        None
+    };
+
+    let profile_mismatch = project_state.get_profile().as_deref() != profile;
 
-    if config_hash.as_ref() != &config_lock.get_project_config_hash()[..] {
+    if (outdated_file.is_some() || profile_mismatch) && auto_refresh {
+        refresh_project_state(project_path, profile);
+        return validate_project_state(project_path, profile, false);
+    }
+
+    if let Some((changed_path, outdated_path)) = outdated_file {
         eprintln!(
-        "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
-        config_path.display(),
-        config_lock_path.display()
-    );
+            "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
+            changed_path.display(),
+            outdated_path.display()
+        );
         exit(exitcode::DATAERR)
     }
 
-    let (project_state, _) = get_config_lock(&project_state_path);
-
-    if config_lock_hash.as_ref() != &project_state.get_project_config_hash()[..] {
+    if profile_mismatch {
         eprintln!(
-        "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
-        config_lock_path.display(),
-        project_state_path.display()
-    );
+            "The selected profile ({}) does not match with the one in '{}' ({}), considering exiting the avatar subshell and entering again",
+            profile.unwrap_or("none"),
+            project_state_path.display(),
+            project_state.get_profile().as_deref().unwrap_or("none")
+        );
         exit(exitcode::DATAERR)
     }
 
-    let binary_configuration = match project_state.get_binary_configuration(&used_program_name) {
-        Some(c) => c,
-        None => {
-            eprintln!(
-                "Binary '{}' not properly configured in lock file '{}'",
-                used_program_name,
-                project_state_path.display()
-            );
-            exit(1)
-        }
-    };
-
-    run_docker_command(
-        binary_configuration,
-        &current_dir,
-        project_path,
-        project_state.get_project_internal_id(),
-        session_token,
-        skip_args,
-    );
+    source_paths.extend(vec![
+        config_path,
+        config_lock_path,
+        local_config_path,
+        project_state_path,
+    ]);
+    RunIndex::new(source_paths, &project_state, project_state_hash.as_ref())
 }
 
 fn run_docker_command(
     binary_configuration: &ImageBinaryConfigLock,
     current_dir: &PathBuf,
     project_path: &PathBuf,
-    project_internal_id: &str,
+    run_index: &RunIndex,
     session_token: &str,
-    skip_args: usize,
+    program_args: Vec<String>,
+    run_overrides: RunOverrides,
 ) {
     if which::which("docker").is_err() {
         eprintln!("docker client is not available");
         exit(exitcode::UNAVAILABLE)
     }
 
-    let mut interactive_options: Vec<&str> = vec!["-i"]; // TODO: Check if stdin is open
-    if atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout) {
-        interactive_options.push("-t")
-    }
+    let project_internal_id = run_index.get_project_internal_id();
+    let project_state_hash = hex::encode(run_index.get_project_state_hash());
 
-    let mut dynamic_env: Vec<String> = Vec::new();
-    let mut dynamic_mounts: Vec<String> = Vec::new();
-    if let Some(run_config) = binary_configuration.get_run_config() {
-        if let Some(used_defined_env_vars) = run_config.get_env() {
-            for (var_name, var_value) in used_defined_env_vars {
-                if var_name == "PATH" {
-                    eprintln!("Passing a custom PATH environment variable is forbidden");
-                    exit(exitcode::USAGE)
-                }
+    let interactive_options = get_interactive_options(binary_configuration, run_overrides);
 
-                dynamic_env.push("--env".to_string());
-                dynamic_env.push(format!("{}={}", var_name, var_value));
+    let working_dir = match binary_configuration.get_workdir_policy() {
+        WorkdirPolicy::Cwd => match current_dir.strip_prefix(project_path) {
+            Ok(wd) => wd.to_path_buf(),
+            Err(_) => {
+                eprintln!("A precondition of run_docker_command does not hold: working directory inside project directory");
+                exit(exitcode::SOFTWARE)
+            }
+        },
+        WorkdirPolicy::ProjectRoot => PathBuf::new(),
+        WorkdirPolicy::Subdir(subdir) => {
+            if !project_path.join(&subdir).is_dir() {
+                eprintln!(
+                    "The working directory {} does not exist",
+                    project_path.join(&subdir).display()
+                );
+                exit(exitcode::NOINPUT)
             }
+            subdir
         }
+    };
 
-        if let Some(host_var_names) = run_config.get_env_from_host() {
-            for var_name in host_var_names {
-                if var_name == "PATH" {
-                    eprintln!("Passing a custom PATH environment variable is forbidden");
-                    exit(exitcode::USAGE)
-                }
+    let container_project_path = get_container_project_path(binary_configuration, project_path);
+    let interpolation_context = InterpolationContext::new(
+        project_path,
+        &container_project_path,
+        project_internal_id,
+        match binary_configuration.get_run_config() {
+            Some(run_config) => run_config.get_strict_interpolation() != &Some(false),
+            None => true,
+        },
+    );
 
-                if let Ok(var_value) = env::var(var_name) {
-                    dynamic_env.push("--env".to_string());
-                    dynamic_env.push(format!("{}={}", var_name, var_value));
-                }
-            }
+    let program_args: Vec<String> = match binary_configuration.get_run_config() {
+        Some(run_config) if run_config.get_translate_arg_paths() == &Some(true) => {
+            let path_mappings = get_path_mappings(
+                run_config,
+                project_path,
+                &container_project_path,
+                &interpolation_context,
+            );
+            let container_roots = get_container_roots(run_config, &interpolation_context);
+            program_args
+                .iter()
+                .map(
+                    |arg| match translate_arg_paths(arg, &path_mappings, &container_roots) {
+                        Some(translated_arg) => translated_arg,
+                        None => {
+                            eprintln!(
+                                "The argument '{}' refers to a host path outside the project directory and the declared bindings",
+                                arg
+                            );
+                            exit(exitcode::USAGE)
+                        }
+                    },
+                )
+                .collect()
         }
+        _ => program_args,
+    };
+
+    let project_name = project_path.file_name().unwrap().to_str().unwrap_or("xxx");
+    let program_name = binary_configuration
+        .get_path()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap_or("yyy");
+
+    // Persistent tools fall back to ephemeral containers outside `avatar shell`
+    let warm_session_owner = match binary_configuration.get_run_config() {
+        Some(run_config) if run_config.get_persistent() == &Some(true) => get_session_owner(),
+        _ => None,
+    };
+    let warm_container_name = format!(
+        "{}_{}_{}_{}_warm",
+        project_name, program_name, project_internal_id, session_token
+    );
+    let container_working_dir = container_project_path.join(working_dir);
+    let container_env =
+        get_container_env_args(binary_configuration, project_path, &interpolation_context);
 
+    // Reusing the container skips its whole setup (ports allocation included)
+    if warm_session_owner.is_some()
+        && is_warm_container_ready(&warm_container_name, &project_state_hash)
+    {
+        exec_in_warm_container(
+            &warm_container_name,
+            interactive_options,
+            &container_env,
+            &container_working_dir,
+            binary_configuration.get_path(),
+            program_args,
+        )
+    }
+
+    let mut dynamic_mounts: Vec<String> = Vec::new();
+    let mut dynamic_limits: Vec<String> = Vec::new();
+    let mut dynamic_network: Vec<String> = Vec::new();
+    if let Some(run_config) = binary_configuration.get_run_config() {
         if let Some(volumes) = run_config.get_volumes() {
             for volume_config in volumes {
                 dynamic_mounts.push("--volume".to_string());
@@ -195,42 +451,63 @@ fn run_docker_command(
             }
         }
 
+        if let Some(resources) = run_config.get_resources() {
+            dynamic_limits = get_resources_args(resources);
+        }
+
+        if let Some(security) = run_config.get_security() {
+            dynamic_limits.extend(get_security_args(
+                security,
+                project_path,
+                &interpolation_context,
+            ));
+        }
+
+        if let Some(network) = run_config.get_network() {
+            dynamic_network.push("--network".to_string());
+            dynamic_network.push(network.clone());
+        }
+
+        if let Some(ports) = run_config.get_ports() {
+            for port_mapping in ports {
+                push_port_args(port_mapping, &mut dynamic_network);
+            }
+        }
+
+        if let Some(tmpfs_mounts) = run_config.get_tmpfs() {
+            for (container_path, tmpfs_config) in tmpfs_mounts {
+                push_tmpfs_args(
+                    container_path,
+                    tmpfs_config,
+                    &interpolation_context,
+                    &mut dynamic_mounts,
+                );
+            }
+        }
+
         if let Some(bindings) = run_config.get_bindings() {
-            for (container_path, host_path) in bindings {
-                dynamic_mounts.push("--mount".to_string());
-                dynamic_mounts.push(format!(
-                    "type=bind,source={},target={}",
-                    host_path.display(),
-                    container_path.display()
-                ));
+            for (container_path, binding_config) in bindings {
+                push_binding_args(
+                    container_path,
+                    binding_config,
+                    project_path,
+                    &interpolation_context,
+                    &mut dynamic_mounts,
+                );
             }
         }
     }
 
-    let working_dir = match current_dir.strip_prefix(project_path) {
-        Ok(wd) => wd,
-        Err(_) => {
-            eprintln!("A precondition of run_docker_command does not hold: working directory inside project directory");
-            exit(exitcode::SOFTWARE)
+    // The implicit mounts are shared by all the project's containers
+    let implicit_mounts_label = match binary_configuration.get_run_config() {
+        Some(run_config) if run_config.get_selinux_relabel() == &Some(true) => {
+            Some(SELinuxLabel::Shared)
         }
-    };
-
-    let process_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
-    let project_name = match project_path.file_name().unwrap().to_str() {
-        Some(pn) => pn,
-        None => "xxx",
-    };
-    let program_name = match binary_configuration
-        .get_path()
-        .file_name()
-        .unwrap()
-        .to_str()
-    {
-        Some(pn) => pn,
-        None => "yyy",
+        _ => None,
     };
 
     let uid = nix::unistd::getuid();
+    let container_user = binary_configuration.get_container_user();
     let home_path = project_path
         .join(CONFIG_DIR_NAME)
         .join(VOLATILE_DIR_NAME)
@@ -242,69 +519,818 @@ fn run_docker_command(
         binary_configuration.get_oci_image_hash()
     );
 
-    Command::new("docker")
-        .args(&["run", "--rm", "--init"])
+    // Shared by the ephemeral and the warm containers (which receive the
+    // environment variables on every `docker exec` instead)
+    let mut container_options: Vec<String> = get_bind_mount_args(
+        project_path,
+        &container_project_path,
+        false,
+        implicit_mounts_label.as_ref(),
+    );
+    container_options.extend(get_container_user_args(
+        &container_user,
+        binary_configuration,
+        &home_path,
+        implicit_mounts_label.as_ref(),
+    ));
+    container_options.extend(vec![
+        "--env".to_string(),
+        format!("{}={}", PROJECT_INTERNAL_ID, project_internal_id),
+        "--env".to_string(),
+        format!("{}={}", SESSION_TOKEN, session_token),
+    ]);
+    container_options.extend(dynamic_mounts);
+    container_options.extend(dynamic_limits);
+    container_options.extend(dynamic_network);
+    container_options.extend(get_user_integration_args(
+        uid,
+        &container_user,
+        &image_ref,
+        project_path,
+        implicit_mounts_label.as_ref(),
+    ));
+
+    if let Some(session_owner) = warm_session_owner {
+        if start_warm_container(
+            &warm_container_name,
+            project_internal_id,
+            &project_state_hash,
+            session_token,
+            session_owner,
+            &container_options,
+            &image_ref,
+        ) {
+            exec_in_warm_container(
+                &warm_container_name,
+                interactive_options,
+                &container_env,
+                &container_working_dir,
+                binary_configuration.get_path(),
+                program_args,
+            )
+        }
+    }
+
+    let process_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+    let container_name = format!(
+        "{}_{}_{}_{}_{}",
+        project_name, program_name, project_internal_id, session_token, process_id
+    );
+    let supervised = match binary_configuration.get_run_config() {
+        Some(run_config) => run_config.get_supervised() == &Some(true),
+        None => false,
+    };
+    let with_tty = interactive_options.contains(&"-t");
+
+    let mut docker_command = Command::new("docker");
+    docker_command
+        .args(if supervised {
+            // The supervisor removes the container and forwards the signals
+            vec!["run", "--init", "--sig-proxy=false"]
+        } else {
+            vec!["run", "--rm", "--init"]
+        })
         .args(interactive_options)
-        .args(dynamic_env)
         .args(&[
             "--name",
-            &format!(
-                "{}_{}_{}_{}_{}",
-                project_name, program_name, project_internal_id, session_token, process_id
-            ),
+            &container_name,
             "--label",
             "managed_tool.container_role.avatar-cli",
             "--label",
             &format!("{}.byid.projects.avatar-cli", project_internal_id),
             "--env",
             &format!("{}={}", PROCESS_ID, process_id),
-            "--env",
-            &format!("{}={}", PROJECT_INTERNAL_ID, project_internal_id),
-            "--env",
-            &format!("{}={}", SESSION_TOKEN, session_token),
-            "--user",
-            &format!("{}:{}", uid, nix::unistd::getgid()),
-            "--mount",
-            &format!(
-                "type=bind,source={},target=/playground",
-                project_path.display() // TODO: Escape commas?
-            ),
             "--workdir",
-            &format!("/playground/{}", working_dir.display()),
-            "--mount",
-            &format!(
-                "type=bind,source={},target={}",
-                home_path.display(), // TODO: Escape commas?
-                CONTAINER_HOME_PATH
-            ),
-            "--env",
-            &format!("HOME={}", CONTAINER_HOME_PATH),
+            &container_working_dir.display().to_string(),
         ])
-        .args(dynamic_mounts)
-        .args(get_user_integration_args(uid, &image_ref, project_path))
+        .args(container_env)
+        .args(container_options)
         .arg(&image_ref)
         .arg(binary_configuration.get_path())
-        .args(env::args().skip(skip_args))
-        .exec(); // Only for UNIX
+        .args(program_args);
+
+    if supervised {
+        supervise(&mut docker_command, &container_name, program_name, with_tty)
+    }
+    docker_command.exec(); // Only for UNIX
+}
+
+/// Precedence: envFile (in order) < env < envFromHost
+fn get_container_env_args(
+    binary_configuration: &ImageBinaryConfigLock,
+    project_path: &Path,
+    interpolation_context: &InterpolationContext,
+) -> Vec<String> {
+    let mut env_args: Vec<String> = Vec::new();
+
+    if let Some(run_config) = binary_configuration.get_run_config() {
+        let mut container_env: BTreeMap<String, String> = BTreeMap::new();
+
+        if let Some(env_files) = run_config.get_env_file() {
+            for env_file in env_files {
+                let env_filepath =
+                    get_host_path(env_file, project_path, interpolation_context, "envFile");
+                container_env.extend(read_env_file(&env_filepath));
+            }
+        }
+
+        if let Some(used_defined_env_vars) = run_config.get_env() {
+            for (var_name, var_value) in used_defined_env_vars {
+                container_env.insert(
+                    var_name.clone(),
+                    interpolation_context
+                        .interpolate_container_value(var_value, &format!("env.{}", var_name)),
+                );
+            }
+        }
+
+        if let Some(host_env_configs) = run_config.get_env_from_host() {
+            for host_env_config in host_env_configs {
+                push_host_env_vars(host_env_config, interpolation_context, &mut container_env);
+            }
+        }
+
+        for (var_name, var_value) in container_env {
+            if var_name == "PATH" {
+                eprintln!("Passing a custom PATH environment variable is forbidden");
+                exit(exitcode::USAGE)
+            }
+
+            env_args.push("--env".to_string());
+            env_args.push(format!("{}={}", var_name, var_value));
+        }
+
+        if let Some(path) =
+            get_container_path_var(binary_configuration, run_config, interpolation_context)
+        {
+            env_args.push("--env".to_string());
+            env_args.push(format!("PATH={}", path));
+        }
+    }
+
+    if let Ok(v) = env::var("TERM") {
+        env_args.push("--env".to_string());
+        env_args.push(format!("TERM={}", v));
+    }
+
+    env_args
+}
+
+fn get_container_project_path(
+    binary_configuration: &ImageBinaryConfigLock,
+    project_path: &PathBuf,
+) -> PathBuf {
+    let project_mount = match binary_configuration.get_run_config() {
+        Some(run_config) => run_config.get_project_mount().clone(),
+        None => None,
+    };
+
+    match project_mount {
+        Some(ProjectMount::HostPath) => {
+            let container_home_path = Path::new(CONTAINER_HOME_PATH);
+            if project_path.starts_with(container_home_path)
+                || container_home_path.starts_with(project_path)
+            {
+                eprintln!(
+                    "The project directory {} can't be mounted at its host path, as it overlaps with {}",
+                    project_path.display(),
+                    CONTAINER_HOME_PATH
+                );
+                exit(exitcode::CONFIG)
+            }
+            project_path.clone()
+        }
+        Some(ProjectMount::Playground) | None => PathBuf::from(CONTAINER_PROJECT_PATH),
+    }
+}
+
+/// The home directory is not mounted for the `image` user, as the image may
+/// rely on its own one.
+fn get_container_user_args(
+    container_user: &ContainerUser,
+    binary_configuration: &ImageBinaryConfigLock,
+    home_path: &Path,
+    implicit_mounts_label: Option<&SELinuxLabel>,
+) -> Vec<String> {
+    let mut user_args: Vec<String> = Vec::new();
+
+    match container_user {
+        ContainerUser::Host => {
+            user_args.push("--user".to_string());
+            user_args.push(format!(
+                "{}:{}",
+                nix::unistd::getuid(),
+                nix::unistd::getgid()
+            ));
+        }
+        ContainerUser::Root => {
+            user_args.push("--user".to_string());
+            user_args.push("0:0".to_string());
+        }
+        ContainerUser::Image => {}
+        ContainerUser::Id(uid, gid) => {
+            user_args.push("--user".to_string());
+            user_args.push(format!("{}:{}", uid, gid));
+        }
+    }
+
+    if let Some(run_config) = binary_configuration.get_run_config() {
+        if let Some(groups) = run_config.get_groups() {
+            for group in groups {
+                user_args.push("--group-add".to_string());
+                user_args.push(group.clone());
+            }
+        }
+    }
+
+    if container_user != &ContainerUser::Image {
+        user_args.extend(get_bind_mount_args(
+            home_path,
+            Path::new(CONTAINER_HOME_PATH),
+            false,
+            implicit_mounts_label,
+        ));
+        user_args.push("--env".to_string());
+        user_args.push(format!("HOME={}", CONTAINER_HOME_PATH));
+    }
+
+    user_args
+}
+
+/// By default, stdin is kept open unless it's closed in the host, and a TTY is
+/// allocated only if stdin and either stdout or stderr are terminals. Docker
+/// merges both output streams into the TTY, so stderr is only kept apart from
+/// stdout when no TTY is allocated.
+fn get_interactive_options(
+    binary_configuration: &ImageBinaryConfigLock,
+    run_overrides: RunOverrides,
+) -> Vec<&'static str> {
+    let (config_interactive, config_tty) = match binary_configuration.get_run_config() {
+        Some(run_config) => (
+            run_config.get_interactive().clone(),
+            run_config.get_tty().clone(),
+        ),
+        None => (None, None),
+    };
+
+    let interactive = match run_overrides.interactive.or(config_interactive) {
+        Some(InteractivityMode::Always) => true,
+        Some(InteractivityMode::Never) => false,
+        Some(InteractivityMode::Auto) | None => is_stdin_open(),
+    };
+    let tty = match run_overrides.tty.or(config_tty) {
+        Some(InteractivityMode::Always) => true,
+        Some(InteractivityMode::Never) => false,
+        Some(InteractivityMode::Auto) | None => {
+            atty::is(atty::Stream::Stdin)
+                && (atty::is(atty::Stream::Stdout) || atty::is(atty::Stream::Stderr))
+        }
+    };
+
+    let mut interactive_options: Vec<&str> = Vec::new();
+    if interactive {
+        interactive_options.push("-i")
+    }
+    if tty {
+        interactive_options.push("-t")
+    }
+    interactive_options
+}
+
+/// Closed standard streams are reopened as /dev/null by the Rust runtime, so
+/// both cases are treated in the same way.
+fn is_stdin_open() -> bool {
+    match (fstat(0), stat("/dev/null")) {
+        (Ok(stdin_stat), Ok(null_stat)) => {
+            let stdin_type = SFlag::from_bits_truncate(stdin_stat.st_mode) & SFlag::S_IFMT;
+            stdin_type != SFlag::S_IFCHR || stdin_stat.st_rdev != null_stat.st_rdev
+        }
+        (Err(_), _) => false,
+        (Ok(_), Err(_)) => true,
+    }
+}
+
+/// Host -> container path mappings used to translate the program arguments,
+/// sorted so the most specific host paths are checked first.
+fn get_path_mappings(
+    run_config: &OCIContainerRunConfigLock,
+    project_path: &Path,
+    container_project_path: &Path,
+    interpolation_context: &InterpolationContext,
+) -> Vec<(PathBuf, PathBuf)> {
+    let mut path_mappings = vec![(
+        project_path.to_path_buf(),
+        container_project_path.to_path_buf(),
+    )];
+
+    if let Some(bindings) = run_config.get_bindings() {
+        for (container_path, binding_config) in bindings {
+            let setting_name = format!("bindings.{}", container_path.display());
+            path_mappings.push((
+                get_host_path(
+                    binding_config.get_source(),
+                    project_path,
+                    interpolation_context,
+                    &setting_name,
+                ),
+                interpolation_context.interpolate_container_path(container_path, &setting_name),
+            ));
+        }
+    }
+
+    path_mappings.sort_by_key(|(host_path, _)| std::cmp::Reverse(host_path.components().count()));
+    path_mappings
+}
+
+/// Container paths that don't come from the host (devices, the home directory,
+/// volumes & tmpfs mounts), the arguments pointing into them are kept as they are.
+fn get_container_roots(
+    run_config: &OCIContainerRunConfigLock,
+    interpolation_context: &InterpolationContext,
+) -> Vec<PathBuf> {
+    let mut container_roots: Vec<PathBuf> =
+        vec![PathBuf::from("/dev"), PathBuf::from(CONTAINER_HOME_PATH)];
+
+    if let Some(volumes) = run_config.get_volumes() {
+        container_roots.extend(volumes.iter().map(|vc| vc.get_container_path().clone()));
+    }
+    if let Some(tmpfs_mounts) = run_config.get_tmpfs() {
+        container_roots.extend(tmpfs_mounts.keys().map(|container_path| {
+            interpolation_context.interpolate_container_path(
+                container_path,
+                &format!("tmpfs.{}", container_path.display()),
+            )
+        }));
+    }
+
+    container_roots
+}
+
+/// Rewrites absolute host paths (as standalone arguments, or as `--option=/path`
+/// values) to their container equivalents. Returns `None` for paths that exist
+/// in the host but are not reachable from the container, while the rest (e.g.
+/// container paths, or patterns like `/foo/`) are passed through unchanged.
+fn translate_arg_paths(
+    arg: &str,
+    path_mappings: &[(PathBuf, PathBuf)],
+    container_roots: &[PathBuf],
+) -> Option<String> {
+    let (prefix, arg_path) = if arg.starts_with('/') {
+        ("", arg)
+    } else if arg.starts_with('-') {
+        match arg.find("=/") {
+            Some(idx) => (&arg[..=idx], &arg[idx + 1..]),
+            None => return Some(arg.to_string()),
+        }
+    } else {
+        return Some(arg.to_string());
+    };
+
+    let arg_path = Path::new(arg_path);
+    for (host_path, container_path) in path_mappings {
+        if let Ok(relative_path) = arg_path.strip_prefix(host_path) {
+            let container_arg_path = if relative_path.as_os_str().is_empty() {
+                container_path.clone()
+            } else {
+                container_path.join(relative_path)
+            };
+            return Some(format!("{}{}", prefix, container_arg_path.display()));
+        }
+    }
+
+    // Paths that already point to the container's filesystem are kept
+    let is_container_path = container_roots
+        .iter()
+        .any(|root| arg_path.starts_with(root))
+        || path_mappings
+            .iter()
+            .any(|(_, container_path)| arg_path.starts_with(container_path));
+    if is_container_path || symlink_metadata(arg_path).is_err() {
+        return Some(arg.to_string());
+    }
+
+    None
+}
+
+/// Interpolates host paths, resolving the relative ones against the project
+/// directory.
+fn get_host_path(
+    path: &Path,
+    project_path: &Path,
+    interpolation_context: &InterpolationContext,
+    setting_name: &str,
+) -> PathBuf {
+    project_path
+        .join(interpolation_context.interpolate_host_path(path, setting_name))
+        .components() // Removes the `.` components
+        .collect()
+}
+
+fn push_binding_args(
+    container_path: &Path,
+    binding_config: &BindingConfig,
+    project_path: &Path,
+    interpolation_context: &InterpolationContext,
+    dynamic_mounts: &mut Vec<String>,
+) {
+    let setting_name = format!("bindings.{}", container_path.display());
+    let source_path = get_host_path(
+        binding_config.get_source(),
+        project_path,
+        interpolation_context,
+        &setting_name,
+    );
+
+    if !source_path.exists() {
+        if !binding_config.must_create() {
+            eprintln!(
+                "The binding source path {} does not exist",
+                source_path.display()
+            );
+            exit(exitcode::NOINPUT)
+        }
+        if create_dir_all(&source_path).is_err() {
+            eprintln!("Unable to create directory {}", source_path.display());
+            exit(exitcode::CANTCREAT)
+        }
+    }
+
+    dynamic_mounts.extend(get_bind_mount_args(
+        &source_path,
+        &interpolation_context.interpolate_container_path(container_path, &setting_name),
+        binding_config.is_read_only(),
+        binding_config.get_selinux_label(),
+    ));
+}
+
+fn push_port_args(port_mapping: &str, dynamic_network: &mut Vec<String>) {
+    let port_mapping = match parse_port_mapping(port_mapping) {
+        Some(pm) => pm,
+        None => {
+            eprintln!("Invalid port mapping: '{}'", port_mapping);
+            exit(exitcode::DATAERR)
+        }
+    };
+
+    let host_ip = match port_mapping.get_host_ip() {
+        Some(host_ip) => host_ip.as_str(),
+        None => "0.0.0.0",
+    };
+    let host_port = match port_mapping.get_host_port() {
+        Some(host_port) => {
+            check_host_port_availability(host_ip, *host_port, port_mapping.get_protocol());
+            *host_port
+        }
+        None => get_free_host_port(host_ip, port_mapping.get_protocol()),
+    };
+
+    eprintln!(
+        "Publishing container port {}/{} on {}:{}",
+        port_mapping.get_container_port(),
+        port_mapping.get_protocol(),
+        host_ip,
+        host_port
+    );
+
+    dynamic_network.push("--publish".to_string());
+    dynamic_network.push(format!(
+        "{}:{}:{}/{}",
+        host_ip,
+        host_port,
+        port_mapping.get_container_port(),
+        port_mapping.get_protocol()
+    ));
+}
+
+/// Asks the OS for an unused port. It's released right away so the container
+/// runtime can bind it.
+fn get_free_host_port(host_ip: &str, protocol: &str) -> u16 {
+    let bind_address = format!("{}:0", host_ip);
+    let local_address = match protocol {
+        "udp" => UdpSocket::bind(&bind_address).and_then(|socket| socket.local_addr()),
+        _ => TcpListener::bind(&bind_address).and_then(|listener| listener.local_addr()),
+    };
+
+    match local_address {
+        Ok(address) => address.port(),
+        Err(e) => {
+            eprintln!("Unable to find a free port on {}:\n\t{}", host_ip, e);
+            exit(exitcode::UNAVAILABLE)
+        }
+    }
+}
+
+/// Fixed host ports can only be published by one container at a time, so
+/// concurrent invocations of the same tool would make the container runtime fail
+/// with an obscure error. Other bind errors (e.g. privileged ports) are left to
+/// the container runtime.
+fn check_host_port_availability(host_ip: &str, host_port: u16, protocol: &str) {
+    let bind_address = format!("{}:{}", host_ip, host_port);
+    let bind_result = match protocol {
+        "udp" => UdpSocket::bind(&bind_address).map(|_| ()),
+        _ => TcpListener::bind(&bind_address).map(|_| ()),
+    };
+
+    if let Err(e) = bind_result {
+        if e.kind() == ErrorKind::AddrInUse {
+            eprintln!(
+                "The host port {}/{} is already in use, probably by another invocation of this tool (use `auto` as host port to allow concurrent invocations)",
+                bind_address, protocol
+            );
+            exit(exitcode::UNAVAILABLE)
+        }
+    }
+}
+
+fn get_security_args(
+    security: &SecurityConfig,
+    project_path: &Path,
+    interpolation_context: &InterpolationContext,
+) -> Vec<String> {
+    let mut security_args: Vec<String> = Vec::new();
+
+    if let Some(capabilities) = security.get_cap_drop() {
+        for capability in capabilities {
+            security_args.push("--cap-drop".to_string());
+            security_args.push(capability.clone());
+        }
+    }
+    if let Some(capabilities) = security.get_cap_add() {
+        for capability in capabilities {
+            security_args.push("--cap-add".to_string());
+            security_args.push(capability.clone());
+        }
+    }
+    if security.get_no_new_privileges() == &Some(true) {
+        security_args.push("--security-opt".to_string());
+        security_args.push("no-new-privileges".to_string());
+    }
+    if security.get_read_only_root_filesystem() == &Some(true) {
+        security_args.push("--read-only".to_string());
+    }
+    if let Some(seccomp_profile) = security.get_seccomp_profile() {
+        let seccomp_profile = if seccomp_profile == Path::new("unconfined") {
+            seccomp_profile.clone()
+        } else {
+            let profile_path = get_host_path(
+                seccomp_profile,
+                project_path,
+                interpolation_context,
+                "security.seccompProfile",
+            );
+            if !profile_path.is_file() {
+                eprintln!(
+                    "The seccomp profile {} does not exist",
+                    profile_path.display()
+                );
+                exit(exitcode::NOINPUT)
+            }
+            profile_path
+        };
+
+        security_args.push("--security-opt".to_string());
+        security_args.push(format!("seccomp={}", seccomp_profile.display()));
+    }
+
+    security_args
+}
+
+fn get_resources_args(resources: &ResourcesConfig) -> Vec<String> {
+    let mut resources_args: Vec<String> = Vec::new();
+
+    if let Some(memory) = resources.get_memory() {
+        resources_args.push("--memory".to_string());
+        resources_args.push(memory.clone());
+    }
+    if let Some(memory_swap) = resources.get_memory_swap() {
+        resources_args.push("--memory-swap".to_string());
+        resources_args.push(memory_swap.clone());
+    }
+    if let Some(cpus) = resources.get_cpus() {
+        resources_args.push("--cpus".to_string());
+        resources_args.push(cpus.to_string());
+    }
+    if let Some(pids_limit) = resources.get_pids_limit() {
+        resources_args.push("--pids-limit".to_string());
+        resources_args.push(pids_limit.to_string());
+    }
+    if let Some(ulimits) = resources.get_ulimits() {
+        for (ulimit_name, ulimit_config) in ulimits {
+            resources_args.push("--ulimit".to_string());
+            resources_args.push(format!(
+                "{}={}:{}",
+                ulimit_name,
+                ulimit_config.get_soft(),
+                ulimit_config.get_hard()
+            ));
+        }
+    }
+
+    resources_args
+}
+
+fn push_tmpfs_args(
+    container_path: &Path,
+    tmpfs_config: &TmpfsConfig,
+    interpolation_context: &InterpolationContext,
+    dynamic_mounts: &mut Vec<String>,
+) {
+    let setting_name = format!("tmpfs.{}", container_path.display());
+    let mut tmpfs_options: Vec<String> = Vec::new();
+
+    if let Some(size) = tmpfs_config.get_size() {
+        tmpfs_options.push(format!("size={}", size));
+    }
+    if let Some(mode) = tmpfs_config.get_mode() {
+        if mode.is_empty() || !mode.chars().all(|c| ('0'..='7').contains(&c)) {
+            eprintln!(
+                "Invalid {}.mode value, it must be an octal number: '{}'",
+                setting_name, mode
+            );
+            exit(exitcode::CONFIG)
+        }
+        tmpfs_options.push(format!("mode={}", mode));
+    }
+
+    let container_path =
+        interpolation_context.interpolate_container_path(container_path, &setting_name);
+    dynamic_mounts.push("--tmpfs".to_string());
+    if tmpfs_options.is_empty() {
+        dynamic_mounts.push(container_path.display().to_string());
+    } else {
+        dynamic_mounts.push(format!(
+            "{}:{}",
+            container_path.display(),
+            tmpfs_options.join(",")
+        ));
+    }
+}
+
+/// SELinux labels can't be expressed with `--mount`, so `--volume` is used for
+/// the relabelled bind mounts.
+fn get_bind_mount_args(
+    source_path: &Path,
+    target_path: &Path,
+    read_only: bool,
+    selinux_label: Option<&SELinuxLabel>,
+) -> Vec<String> {
+    match selinux_label {
+        Some(label) => vec![
+            "--volume".to_string(),
+            format!(
+                "{}:{}:{}{}",
+                source_path.display(),
+                target_path.display(),
+                if read_only { "ro," } else { "" },
+                label.get_mount_option()
+            ),
+        ],
+        None => vec![
+            "--mount".to_string(),
+            format!(
+                "type=bind,source={},target={}{}",
+                source_path.display(), // TODO: Escape commas?
+                target_path.display(),
+                if read_only { ",readonly" } else { "" }
+            ),
+        ],
+    }
+}
+
+/// Extends the image's PATH with the pathPrepend & pathAppend directories
+fn get_container_path_var(
+    binary_configuration: &ImageBinaryConfigLock,
+    run_config: &OCIContainerRunConfigLock,
+    interpolation_context: &InterpolationContext,
+) -> Option<String> {
+    let empty: Vec<String> = Vec::new();
+    let path_prepend = run_config.get_path_prepend().as_ref().unwrap_or(&empty);
+    let path_append = run_config.get_path_append().as_ref().unwrap_or(&empty);
+    if path_prepend.is_empty() && path_append.is_empty() {
+        return None;
+    }
+
+    let image_path = match binary_configuration.get_oci_image_env_path() {
+        Some(image_path) => image_path.clone(),
+        None => DEFAULT_CONTAINER_PATH.to_string(),
+    };
+
+    let interpolate = |dirs: &Vec<String>, setting_name: &str| -> Vec<String> {
+        dirs.iter()
+            .map(|dir| {
+                let dir = interpolation_context.interpolate_container_value(dir, setting_name);
+                if dir.is_empty() || dir.contains(':') {
+                    eprintln!("Invalid {} entry: '{}'", setting_name, dir);
+                    exit(exitcode::CONFIG)
+                }
+                dir
+            })
+            .collect()
+    };
+
+    let mut path_dirs = interpolate(path_prepend, "pathPrepend");
+    path_dirs.push(image_path);
+    path_dirs.extend(interpolate(path_append, "pathAppend"));
+
+    Some(path_dirs.join(":"))
+}
+
+fn push_host_env_vars(
+    host_env_config: &EnvFromHostConfig,
+    interpolation_context: &InterpolationContext,
+    container_env: &mut BTreeMap<String, String>,
+) {
+    match host_env_config {
+        EnvFromHostConfig::Name(var_name) if var_name.contains('*') => {
+            for (host_var_name, var_value) in env::vars() {
+                // Patterns never pass the reserved variables, even if they match them
+                if !is_reserved_env_var(&host_var_name)
+                    && matches_var_pattern(var_name, &host_var_name)
+                {
+                    container_env.insert(host_var_name, var_value);
+                }
+            }
+        }
+        EnvFromHostConfig::Name(var_name) => {
+            if let Ok(var_value) = env::var(var_name) {
+                container_env.insert(var_name.clone(), var_value);
+            }
+        }
+        EnvFromHostConfig::Mapping(mapping) => {
+            if mapping.get_name().contains('*') || mapping.get_from().contains('*') {
+                eprintln!(
+                    "Patterns are not allowed in renamed envFromHost entries ('{}')",
+                    mapping.get_name()
+                );
+                exit(exitcode::CONFIG)
+            }
+
+            match env::var(mapping.get_from()) {
+                Ok(var_value) => {
+                    container_env.insert(mapping.get_name().clone(), var_value);
+                }
+                Err(_) => {
+                    if let Some(default_value) = mapping.get_default() {
+                        container_env.insert(
+                            mapping.get_name().clone(),
+                            interpolation_context.interpolate_container_value(
+                                default_value,
+                                &format!("envFromHost.{}.default", mapping.get_name()),
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_reserved_env_var(var_name: &str) -> bool {
+    RESERVED_ENV_VAR_NAMES.contains(&var_name) || var_name.starts_with(RESERVED_ENV_VAR_PREFIX)
+}
+
+/// Matches variable names against simple patterns, where `*` matches any
+/// sequence of characters.
+fn matches_var_pattern(pattern: &str, var_name: &str) -> bool {
+    let mut pattern_parts = pattern.split('*');
+    let first_part = pattern_parts.next().unwrap_or("");
+    if !var_name.starts_with(first_part) {
+        return false;
+    }
+
+    let mut remaining_name = &var_name[first_part.len()..];
+    let mut pattern_parts: Vec<&str> = pattern_parts.collect();
+    let last_part = match pattern_parts.pop() {
+        Some(p) => p,
+        None => return remaining_name.is_empty(), // The pattern has no wildcards
+    };
+
+    for part in pattern_parts {
+        match remaining_name.find(part) {
+            Some(i) => remaining_name = &remaining_name[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    remaining_name.ends_with(last_part)
 }
 
 fn get_user_integration_args(
     uid: nix::unistd::Uid,
+    container_user: &ContainerUser,
     image_ref: &str,
-    project_path: &PathBuf,
+    project_path: &Path,
+    implicit_mounts_label: Option<&SELinuxLabel>,
 ) -> Vec<String> {
     let mut dynamic_args: Vec<String> = vec![];
 
-    if let Ok(v) = env::var("TERM") {
-        dynamic_args.push("--env".to_string());
-        dynamic_args.push(format!("TERM={}", v));
-    }
-
-    if let Ok(Some(user)) = nix::unistd::User::from_uid(uid) {
-        dynamic_args.push("--env".to_string());
-        dynamic_args.push(format!("USER={}", user.name));
-        dynamic_args.push("--env".to_string());
-        dynamic_args.push(format!("USERNAME={}", user.name));
+    if container_user == &ContainerUser::Host {
+        if let Ok(Some(user)) = nix::unistd::User::from_uid(uid) {
+            dynamic_args.push("--env".to_string());
+            dynamic_args.push(format!("USER={}", user.name));
+            dynamic_args.push("--env".to_string());
+            dynamic_args.push(format!("USERNAME={}", user.name));
+        }
     }
 
     #[cfg(target_os = "linux")]
@@ -316,12 +1342,25 @@ fn get_user_integration_args(
     #[cfg(target_os = "macos")]
     push_ssh_agent_socket_args(&mut dynamic_args);
 
-    if let Some(home_dir) = dirs::home_dir() {
-        push_home_config_args(&home_dir, ".ssh", &mut dynamic_args);
-        push_home_config_args(&home_dir, ".gnupg", &mut dynamic_args);
+    // The image's own user has its own home directory, instead of Avatar's one
+    if container_user != &ContainerUser::Image {
+        if let Some(home_dir) = dirs::home_dir() {
+            push_home_config_args(&home_dir, ".ssh", &mut dynamic_args);
+            push_home_config_args(&home_dir, ".gnupg", &mut dynamic_args);
+        }
     }
 
-    push_passwd_args(image_ref, project_path, &mut dynamic_args);
+    // The generated passwd files only have entries for the host user and the
+    // explicit uids, root & the image user are already defined by the image
+    match container_user {
+        ContainerUser::Host | ContainerUser::Id(_, _) => push_passwd_args(
+            image_ref,
+            project_path,
+            implicit_mounts_label,
+            &mut dynamic_args,
+        ),
+        ContainerUser::Root | ContainerUser::Image => {}
+    }
     push_git_args(&mut dynamic_args);
 
     dynamic_args
@@ -355,7 +1394,12 @@ fn push_git_args(dynamic_args: &mut Vec<String>) {
     }
 }
 
-fn push_passwd_args(image_ref: &str, project_path: &PathBuf, dynamic_args: &mut Vec<String>) {
+fn push_passwd_args(
+    image_ref: &str,
+    project_path: &Path,
+    implicit_mounts_label: Option<&SELinuxLabel>,
+    dynamic_args: &mut Vec<String>,
+) {
     let passwd_path = project_path
         .join(CONFIG_DIR_NAME)
         .join(VOLATILE_DIR_NAME)
@@ -371,10 +1415,11 @@ fn push_passwd_args(image_ref: &str, project_path: &PathBuf, dynamic_args: &mut
             exit(exitcode::USAGE)
         }
 
-        dynamic_args.push("--mount".to_string());
-        dynamic_args.push(format!(
-            "type=bind,source={},target=/etc/passwd",
-            passwd_path.display()
+        dynamic_args.extend(get_bind_mount_args(
+            &passwd_path,
+            Path::new("/etc/passwd"),
+            false,
+            implicit_mounts_label,
         ));
     }
 }
@@ -420,3 +1465,109 @@ fn push_home_config_args(home_dir: &PathBuf, config_name: &str, dynamic_args: &m
         ));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_dir;
+    use std::fs::{create_dir, remove_dir_all};
+
+    #[test]
+    fn patterns_without_wildcards_match_whole_names() {
+        assert!(matches_var_pattern("NPM_TOKEN", "NPM_TOKEN"));
+        assert!(!matches_var_pattern("NPM_TOKEN", "NPM_TOKENS"));
+        assert!(!matches_var_pattern("NPM_TOKEN", "MY_NPM_TOKEN"));
+    }
+
+    #[test]
+    fn wildcards_match_any_sequence() {
+        assert!(matches_var_pattern("NPM_*", "NPM_"));
+        assert!(matches_var_pattern("NPM_*", "NPM_CONFIG_CACHE"));
+        assert!(!matches_var_pattern("NPM_*", "XNPM_CONFIG"));
+        assert!(matches_var_pattern("*_TOKEN", "NPM_TOKEN"));
+        assert!(!matches_var_pattern("*_TOKEN", "NPM_TOKENS"));
+        assert!(matches_var_pattern("AWS_*_ID", "AWS_ACCESS_KEY_ID"));
+        assert!(matches_var_pattern("*", "ANYTHING"));
+        assert!(matches_var_pattern("A*B*C", "AxxBxxC"));
+        assert!(!matches_var_pattern("A*B*C", "AxxCxxB"));
+    }
+
+    #[test]
+    fn overlapping_parts_are_not_matched_twice() {
+        assert!(!matches_var_pattern("AB*BC", "ABC"));
+        assert!(matches_var_pattern("AB*BC", "ABBC"));
+        assert!(!matches_var_pattern("A*A", "A"));
+    }
+
+    #[test]
+    fn arg_paths_are_translated_to_their_container_equivalents() {
+        let path_mappings = vec![
+            (PathBuf::from("/home/u/prj/data"), PathBuf::from("/data")),
+            (PathBuf::from("/home/u/prj"), PathBuf::from("/playground")),
+        ];
+
+        for (arg, translated_arg) in &[
+            ("/home/u/prj", "/playground"),
+            ("/home/u/prj/src/main.rs", "/playground/src/main.rs"),
+            ("/home/u/prj/data/in.csv", "/data/in.csv"),
+            ("--input=/home/u/prj/data", "--input=/data"),
+            ("relative/path", "relative/path"),
+            ("--verbose", "--verbose"),
+        ] {
+            assert_eq!(
+                translate_arg_paths(arg, &path_mappings, &[]),
+                Some(translated_arg.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn only_existing_host_paths_outside_the_mappings_are_rejected() {
+        let test_dir = create_test_dir();
+        let path_mappings = vec![(test_dir.join("prj"), PathBuf::from("/playground"))];
+        let container_roots = vec![PathBuf::from("/dev"), test_dir.join("cache")];
+        create_dir(test_dir.join("cache")).unwrap();
+        create_dir(test_dir.join("other")).unwrap();
+
+        let kept_args = vec![
+            "/pattern/".to_string(),
+            "/playground/src".to_string(),
+            "/dev/null".to_string(),
+            test_dir.join("cache").join("pkg").display().to_string(),
+            format!("--out={}", test_dir.join("missing").display()),
+        ];
+        for arg in &kept_args {
+            assert_eq!(
+                translate_arg_paths(arg, &path_mappings, &container_roots),
+                Some(arg.clone())
+            );
+        }
+
+        for arg in &[
+            test_dir.join("other").display().to_string(),
+            format!("--out={}", test_dir.display()),
+        ] {
+            assert_eq!(
+                translate_arg_paths(arg, &path_mappings, &container_roots),
+                None
+            );
+        }
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn reserved_variables_are_detected() {
+        for var_name in &[
+            "HOME",
+            "PATH",
+            "USER",
+            "USERNAME",
+            "AVATAR_CLI_SESSION_TOKEN",
+        ] {
+            assert!(is_reserved_env_var(var_name));
+        }
+        for var_name in &["HOMEBREW_PREFIX", "MY_PATH", "AVATAR_PROJECT_DIR"] {
+            assert!(!is_reserved_env_var(var_name));
+        }
+    }
+}
@@ -5,22 +5,48 @@
  */
 
 use std::env;
-use std::os::unix::process::CommandExt; // Brings trait that allows us to use exec
+use std::os::unix::process::CommandExt; // Brings trait that allows us to use pre_exec
 use std::process::{exit, Command};
+use std::sync::atomic::{AtomicI32, Ordering};
 
+use nix::libc::{self, c_int};
+use nix::sys::signal::{sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 use crate::avatar_env::{
-    CONFIG_LOCK_PATH, CONFIG_PATH, PROJECT_INTERNAL_ID, PROJECT_PATH, SESSION_TOKEN, STATE_PATH,
+    CONFIG_LOCK_PATH, CONFIG_PATH, PROFILE, PROJECT_INTERNAL_ID, PROJECT_PATH, SESSION_PID,
+    SESSION_TOKEN, STATE_PATH,
 };
 use crate::{
     directories::{CONFIG_DIR_NAME, VOLATILE_DIR_NAME},
     subcommands::install::install_subcommand,
+    warm_containers::remove_session_containers,
 };
 
-pub(crate) fn shell_subcommand() {
+static SHELL_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal_number: c_int) {
+    let shell_pid = SHELL_PID.load(Ordering::SeqCst);
+    if shell_pid > 0 {
+        unsafe { libc::kill(shell_pid, signal_number) };
+    }
+}
+
+/// Tears down the session's persistent containers, however the subshell ends.
+struct SessionContainersGuard<'a> {
+    project_internal_id: &'a str,
+    session_token: &'a str,
+}
+
+impl Drop for SessionContainersGuard<'_> {
+    fn drop(&mut self) {
+        remove_session_containers(self.project_internal_id, self.session_token);
+    }
+}
+
+pub(crate) fn shell_subcommand(profile: Option<&str>) {
     let (project_path, config_path, config_lock_path, project_state_path, project_state) =
-        install_subcommand(true);
+        install_subcommand(true, profile);
 
     let shell_path = match env::var("SHELL") {
         Ok(sp) => sp,
@@ -30,10 +56,7 @@ pub(crate) fn shell_subcommand() {
     let path_var = match env::var("PATH") {
         Ok(p) => p,
         Err(e) => {
-            eprintln!(
-                "Unable to load PATH environment variable\n\n{}\n",
-                e.to_string()
-            );
+            eprintln!("Unable to load PATH environment variable\n\n{}\n", e);
             exit(exitcode::OSERR)
         }
     };
@@ -45,28 +68,81 @@ pub(crate) fn shell_subcommand() {
 
     let session_token: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
 
-    Command::new(shell_path)
+    let mut shell_command = Command::new(shell_path);
+    match profile {
+        Some(profile_name) => shell_command.env(PROFILE, profile_name),
+        None => shell_command.env_remove(PROFILE),
+    };
+
+    shell_command
         .env("PATH", path_var)
         .env(CONFIG_PATH, config_path)
         .env(CONFIG_LOCK_PATH, config_lock_path)
         .env(PROJECT_PATH, project_path)
         .env(PROJECT_INTERNAL_ID, project_state.get_project_internal_id())
-        .env(SESSION_TOKEN, session_token)
-        .env(STATE_PATH, project_state_path)
-        .exec();
+        .env(SESSION_PID, std::process::id().to_string())
+        .env(SESSION_TOKEN, &session_token)
+        .env(STATE_PATH, project_state_path);
+
+    // Avatar waits for the subshell (as `system` does) to tear down the session's
+    // persistent containers once it finishes. The hangups (e.g. closing the
+    // terminal) and terminations are forwarded to the subshell instead.
+    let forward_action = SigAction::new(
+        SigHandler::Handler(forward_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe {
+        let _ = signal(Signal::SIGINT, SigHandler::SigIgn);
+        let _ = signal(Signal::SIGQUIT, SigHandler::SigIgn);
+        for forwarded_signal in &[Signal::SIGHUP, Signal::SIGTERM] {
+            if sigaction(*forwarded_signal, &forward_action).is_err() {
+                eprintln!("Unable to install the signal handlers");
+                exit(exitcode::OSERR)
+            }
+        }
+        shell_command.pre_exec(|| {
+            let _ = signal(Signal::SIGINT, SigHandler::SigDfl);
+            let _ = signal(Signal::SIGQUIT, SigHandler::SigDfl);
+            Ok(())
+        });
+    }
+
+    let session_guard = SessionContainersGuard {
+        project_internal_id: project_state.get_project_internal_id(),
+        session_token: &session_token,
+    };
+
+    let mut shell_process = match shell_command.spawn() {
+        Ok(process) => process,
+        Err(e) => {
+            eprintln!("Unable to start the shell\n\n{}\n", e);
+            exit(exitcode::OSERR)
+        }
+    };
+    SHELL_PID.store(shell_process.id() as i32, Ordering::SeqCst);
+
+    let shell_status = match shell_process.wait() {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Unable to wait for the shell\n\n{}\n", e);
+            drop(session_guard);
+            exit(exitcode::OSERR)
+        }
+    };
+
+    drop(session_guard); // `exit` doesn't run the destructors
+    exit(shell_status.code().unwrap_or(exitcode::OSERR))
 }
 
-pub(crate) fn export_env_subcommand() {
+pub(crate) fn export_env_subcommand(profile: Option<&str>) {
     let (project_path, config_path, config_lock_path, project_state_path, project_state) =
-        install_subcommand(false);
+        install_subcommand(false, profile);
 
     let path_var = match env::var("PATH") {
         Ok(p) => p,
         Err(e) => {
-            eprintln!(
-                "Unable to load PATH environment variable\n\n{}\n",
-                e.to_string()
-            );
+            eprintln!("Unable to load PATH environment variable\n\n{}\n", e);
             exit(exitcode::OSERR)
         }
     };
@@ -76,6 +152,8 @@ pub(crate) fn export_env_subcommand() {
         .join("bin");
     let path_var = format!("{}:{}", avatar_bin_path.display(), path_var);
 
+    // Without `avatar shell` there's no session owner to tear down persistent
+    // containers, so the tools run in ephemeral containers instead.
     let session_token: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
 
     println!("export PATH=\"{}\"", path_var);
@@ -92,5 +170,10 @@ pub(crate) fn export_env_subcommand() {
         project_state.get_project_internal_id()
     );
     println!("export {}=\"{}\"", SESSION_TOKEN, session_token);
+    println!("unset {}", SESSION_PID);
+    match profile {
+        Some(profile_name) => println!("export {}=\"{}\"", PROFILE, profile_name),
+        None => println!("unset {}", PROFILE),
+    }
     println!("export {}=\"{}\"", STATE_PATH, project_state_path.display());
 }
@@ -0,0 +1,185 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::io;
+use std::os::unix::process::CommandExt; // Brings trait that allows us to use pre_exec
+use std::process::{exit, Command};
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+const POLLING_INTERVAL: Duration = Duration::from_millis(50);
+
+const FORWARDED_SIGNALS: [Signal; 5] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGWINCH,
+];
+
+static PENDING_SIGNALS: [AtomicBool; 5] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+extern "C" fn record_signal(signal_number: c_int) {
+    for (idx, forwarded_signal) in FORWARDED_SIGNALS.iter().enumerate() {
+        if *forwarded_signal as c_int == signal_number {
+            PENDING_SIGNALS[idx].store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Runs the container without replacing the current process, so avatar can
+/// forward the signals it receives, tell runtime failures apart from the tool's
+/// own ones, and remove the container once it finishes. The `docker_command`
+/// must create a container named `container_name` without the `--rm` flag.
+pub(crate) fn supervise(
+    docker_command: &mut Command,
+    container_name: &str,
+    program_name: &str,
+    with_tty: bool,
+) -> ! {
+    // The docker client ignores the signals that avatar forwards, so they are
+    // not delivered twice when they are sent to the whole process group.
+    unsafe {
+        docker_command.pre_exec(|| {
+            for forwarded_signal in FORWARDED_SIGNALS.iter() {
+                if *forwarded_signal != Signal::SIGWINCH {
+                    signal(*forwarded_signal, SigHandler::SigIgn)
+                        .map_err(|_| io::Error::last_os_error())?;
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let record_action = SigAction::new(
+        SigHandler::Handler(record_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for forwarded_signal in FORWARDED_SIGNALS.iter() {
+        if unsafe { sigaction(*forwarded_signal, &record_action) }.is_err() {
+            eprintln!("Unable to install the signal handlers");
+            exit(exitcode::OSERR)
+        }
+    }
+
+    let child = match docker_command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Unable to start the docker client:\n\t{}", e);
+            exit(exitcode::UNAVAILABLE)
+        }
+    };
+
+    // Polling avoids losing the signals received right before blocking on waitpid
+    let client_exit_code = loop {
+        forward_pending_signals(container_name, with_tty);
+
+        match waitpid(Pid::from_raw(child.id() as i32), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, exit_code)) => break exit_code,
+            Ok(WaitStatus::Signaled(_, client_signal, _)) => break 128 + client_signal as i32,
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => sleep(POLLING_INTERVAL),
+            Err(e) => {
+                eprintln!("Unable to wait for the docker client:\n\t{}", e);
+                remove_container(container_name);
+                exit(exitcode::OSERR)
+            }
+        }
+    };
+
+    let exit_code = get_container_exit_code(container_name, program_name, client_exit_code);
+    remove_container(container_name);
+    exit(exit_code)
+}
+
+/// When a TTY is allocated, the docker client already resizes it on SIGWINCH
+/// (and the tool receives the signal through it).
+fn forward_pending_signals(container_name: &str, with_tty: bool) {
+    for (idx, forwarded_signal) in FORWARDED_SIGNALS.iter().enumerate() {
+        if !PENDING_SIGNALS[idx].swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        if with_tty && *forwarded_signal == Signal::SIGWINCH {
+            continue;
+        }
+
+        // The container may not exist yet, or have finished already
+        let _ = Command::new("docker")
+            .args([
+                "kill",
+                "--signal",
+                forwarded_signal.as_ref(),
+                container_name,
+            ])
+            .output();
+    }
+}
+
+/// Passes through the tool's exit code, unless the container couldn't be
+/// created or started.
+fn get_container_exit_code(container_name: &str, program_name: &str, client_exit_code: i32) -> i32 {
+    let output = match Command::new("docker")
+        .args([
+            "inspect",
+            "--format={{.State.ExitCode}}\t{{.State.Error}}",
+            container_name,
+        ])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Unable to inspect container {}:\n\t{}", container_name, e);
+            return exitcode::UNAVAILABLE;
+        }
+    };
+
+    if !output.status.success() {
+        eprintln!(
+            "The container runtime was unable to create the container for '{}' (exit code {})",
+            program_name, client_exit_code
+        );
+        return exitcode::UNAVAILABLE;
+    }
+
+    let state = from_utf8(&output.stdout).unwrap_or("").trim_end();
+    let (exit_code, runtime_error) = match state.find('\t') {
+        Some(idx) => (&state[..idx], state[idx + 1..].trim()),
+        None => (state, ""),
+    };
+
+    if !runtime_error.is_empty() {
+        eprintln!(
+            "The container runtime was unable to run '{}':\n\t{}",
+            program_name, runtime_error
+        );
+        return exitcode::UNAVAILABLE;
+    }
+
+    match exit_code.parse::<i32>() {
+        Ok(exit_code) => exit_code,
+        Err(_) => client_exit_code,
+    }
+}
+
+fn remove_container(container_name: &str) {
+    let _ = Command::new("docker")
+        .args(["rm", "--force", container_name])
+        .output();
+}
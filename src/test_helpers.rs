@@ -0,0 +1,19 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::fs::create_dir;
+use std::path::PathBuf;
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+/// Creates an empty directory under the system's temporary directory. Tests
+/// must remove it once they're done.
+pub(crate) fn create_test_dir() -> PathBuf {
+    let dir_name: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+    let test_dir = std::env::temp_dir().join(format!("avatar-cli-test-{}", dir_name));
+    create_dir(&test_dir).unwrap();
+    test_dir
+}
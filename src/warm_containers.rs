@@ -0,0 +1,247 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::env;
+use std::os::unix::process::CommandExt; // Brings trait that allows us to use exec
+use std::path::Path;
+use std::process::{exit, Command};
+use std::str::from_utf8;
+
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::avatar_env::{PROCESS_ID, SESSION_PID};
+
+const WARM_CONTAINER_ROLE_LABEL: &str = "warm_tool.container_role.avatar-cli";
+const SESSION_OWNER_LABEL: &str = "session_owner.avatar-cli";
+
+fn get_project_label(project_internal_id: &str) -> String {
+    format!("{}.byid.projects.avatar-cli", project_internal_id)
+}
+
+fn get_session_label(session_token: &str) -> String {
+    format!("{}.bysession.avatar-cli", session_token)
+}
+
+fn get_state_label(project_state_hash: &str) -> String {
+    format!("{}.bystate.avatar-cli", project_state_hash)
+}
+
+/// Warm containers are only used from the sessions started by `avatar shell`,
+/// which remove them once they finish. Returns the process id of the session's
+/// owner, or `None` when there's no live owner to tear them down (as with
+/// `avatar run` in scripts, or the environments from `avatar export-env`).
+pub(crate) fn get_session_owner() -> Option<i32> {
+    let session_owner: i32 = env::var(SESSION_PID).ok()?.parse().ok()?;
+
+    match is_process_alive(session_owner) {
+        true => Some(session_owner),
+        false => None,
+    }
+}
+
+pub(crate) fn is_process_alive(process_id: i32) -> bool {
+    if process_id <= 0 {
+        return false;
+    }
+
+    match kill(Pid::from_raw(process_id), None) {
+        Ok(_) => true,
+        // The process exists, but it belongs to another user
+        Err(e) => e.as_errno() == Some(Errno::EPERM),
+    }
+}
+
+/// A warm container is only reused if it's running and it was created with the
+/// current project state.
+pub(crate) fn is_warm_container_ready(container_name: &str, project_state_hash: &str) -> bool {
+    !list_containers(&[
+        format!("name=^/{}$", container_name),
+        format!("label={}", get_state_label(project_state_hash)),
+        "status=running".to_string(),
+    ])
+    .is_empty()
+}
+
+/// Starts a long-lived container that just waits for `docker exec` calls. The
+/// `container_options` are the same ones used for the ephemeral containers.
+/// Returns false when it can't be started (e.g. images without `sleep`, like
+/// the distroless ones), so the caller falls back to an ephemeral container.
+pub(crate) fn start_warm_container(
+    container_name: &str,
+    project_internal_id: &str,
+    project_state_hash: &str,
+    session_token: &str,
+    session_owner: i32,
+    container_options: &[String],
+    image_ref: &str,
+) -> bool {
+    for _ in 0..2 {
+        let output = match Command::new("docker")
+            .args([
+                "run",
+                "--detach",
+                "--init",
+                "--name",
+                container_name,
+                "--label",
+                WARM_CONTAINER_ROLE_LABEL,
+                "--label",
+                &get_project_label(project_internal_id),
+                "--label",
+                &get_session_label(session_token),
+                "--label",
+                &get_state_label(project_state_hash),
+                "--label",
+                &format!("{}={}", SESSION_OWNER_LABEL, session_owner),
+                "--entrypoint",
+                "sleep",
+            ])
+            .args(container_options)
+            .args([image_ref, "infinity"])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Unable to start the docker client:\n\t{}", e);
+                exit(exitcode::UNAVAILABLE)
+            }
+        };
+
+        if output.status.success() {
+            return true;
+        }
+
+        // Another invocation may have started it in the meantime
+        if is_warm_container_ready(container_name, project_state_hash) {
+            return true;
+        }
+
+        // The existing container is stopped, or belongs to an outdated state.
+        // After the second attempt, it's the one that couldn't be started.
+        remove_containers(&[container_name.to_string()]);
+    }
+    false
+}
+
+/// The environment variables are passed on every invocation (instead of when
+/// the warm container starts), so they're never outdated.
+pub(crate) fn exec_in_warm_container(
+    container_name: &str,
+    interactive_options: Vec<&str>,
+    container_env: &[String],
+    working_dir: &Path,
+    binary_path: &Path,
+    program_args: Vec<String>,
+) -> ! {
+    let process_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+
+    let error = Command::new("docker")
+        .arg("exec")
+        .args(interactive_options)
+        .args(container_env)
+        .args([
+            "--env",
+            &format!("{}={}", PROCESS_ID, process_id),
+            "--workdir",
+            &working_dir.display().to_string(),
+            container_name,
+        ])
+        .arg(binary_path)
+        .args(program_args)
+        .exec(); // Only for UNIX
+
+    eprintln!("Unable to start the docker client:\n\t{}", error);
+    exit(exitcode::UNAVAILABLE)
+}
+
+/// Called when the avatar subshell finishes
+pub(crate) fn remove_session_containers(project_internal_id: &str, session_token: &str) {
+    remove_containers(&list_containers(&[
+        format!("label={}", WARM_CONTAINER_ROLE_LABEL),
+        format!("label={}", get_project_label(project_internal_id)),
+        format!("label={}", get_session_label(session_token)),
+    ]))
+}
+
+/// Removes the project's warm containers whose session owner is gone (killed
+/// before it could tear down its session). The ones from live sessions created
+/// with a previous state are replaced on their next invocation.
+pub(crate) fn prune_orphan_warm_containers(project_internal_id: &str) {
+    let orphan_containers: Vec<String> = list_containers_with_owner(&[
+        format!("label={}", WARM_CONTAINER_ROLE_LABEL),
+        format!("label={}", get_project_label(project_internal_id)),
+    ])
+    .into_iter()
+    .filter(|(_, session_owner)| match session_owner {
+        Some(session_owner) => !is_process_alive(*session_owner),
+        None => true, // Started by a previous version, without an owner
+    })
+    .map(|(container_id, _)| container_id)
+    .collect();
+
+    remove_containers(&orphan_containers)
+}
+
+fn list_containers(filters: &[String]) -> Vec<String> {
+    run_list_command(filters, None)
+}
+
+fn list_containers_with_owner(filters: &[String]) -> Vec<(String, Option<i32>)> {
+    run_list_command(
+        filters,
+        Some(&format!(
+            "{{{{.ID}}}}\t{{{{.Label \"{}\"}}}}",
+            SESSION_OWNER_LABEL
+        )),
+    )
+    .iter()
+    .map(|line| {
+        let mut fields = line.splitn(2, '\t');
+        let container_id = fields.next().unwrap_or("").to_string();
+        let session_owner = fields.next().and_then(|pid| pid.trim().parse().ok());
+        (container_id, session_owner)
+    })
+    .collect()
+}
+
+fn run_list_command(filters: &[String], format: Option<&str>) -> Vec<String> {
+    let mut list_command = Command::new("docker");
+    list_command.args(["ps", "--all", "--no-trunc"]);
+    match format {
+        Some(format) => list_command.args(["--format", format]),
+        None => list_command.arg("--quiet"),
+    };
+    for filter in filters {
+        list_command.args(["--filter", filter]);
+    }
+
+    match list_command.output() {
+        Ok(output) if output.status.success() => from_utf8(&output.stdout)
+            .unwrap_or("")
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn remove_containers(containers: &[String]) {
+    if containers.is_empty() {
+        return;
+    }
+
+    if let Err(e) = Command::new("docker")
+        .args(["rm", "--force"])
+        .args(containers)
+        .output()
+    {
+        eprintln!("Unable to remove persistent containers\n\n{}\n", e);
+    }
+}